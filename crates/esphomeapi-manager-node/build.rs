@@ -1,5 +1,3 @@
-use napi_build;
-
 fn main() {
   napi_build::setup();
 }
@@ -35,7 +35,12 @@ export interface ConnectionOptions {
   expectedName?: string
   psk?: string
   clientInfo?: string
+  /** Seconds between two pings, 0 disables them */
   keepAliveDuration?: number
+  /** Milliseconds allowed for opening the socket */
+  connectTimeout?: number
+  /** Milliseconds allowed for the device to answer a request */
+  requestTimeout?: number
 }
 
 export declare function discover(seconds: number): Promise<Array<ServiceInfo>>
@@ -1,4 +1,5 @@
 use esphomeapi_manager::ServiceInfo as RustServiceInfo;
+use napi::bindgen_prelude::*;
 use napi_derive::napi;
 
 #[napi(object)]
@@ -33,10 +34,14 @@ impl From<RustServiceInfo> for ServiceInfo {
 }
 
 #[napi]
-pub async fn discover(seconds: u32) -> Vec<ServiceInfo> {
-  let result = esphomeapi_manager::discover(seconds).await;
-  result
-    .iter()
-    .map(|service_info| service_info.clone().into())
-    .collect()
+pub async fn discover(seconds: u32) -> Result<Vec<ServiceInfo>> {
+  let result = esphomeapi_manager::discover(seconds)
+    .await
+    .map_err(|e| Error::new(Status::GenericFailure, e.to_string()))?;
+  Ok(
+    result
+      .iter()
+      .map(|service_info| service_info.clone().into())
+      .collect(),
+  )
 }
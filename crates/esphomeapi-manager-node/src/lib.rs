@@ -1,3 +1,3 @@
 pub mod discovery;
 mod entity;
-mod manager;
+pub mod manager;
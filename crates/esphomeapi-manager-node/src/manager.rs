@@ -1,4 +1,6 @@
-use esphomeapi_manager::{entity::BaseEntity, Manager as RustManager};
+use std::time::Duration;
+
+use esphomeapi_manager::{entity::BaseEntity, ClientOptions, Manager as RustManager, Psk};
 use napi::bindgen_prelude::*;
 use napi_derive::napi;
 
@@ -12,7 +14,47 @@ pub struct ConnectionOptions {
   pub expected_name: Option<String>,
   pub psk: Option<String>,
   pub client_info: Option<String>,
+  /// Seconds between two pings, 0 disables them
   pub keep_alive_duration: Option<u32>,
+  /// Milliseconds allowed for opening the socket
+  pub connect_timeout: Option<u32>,
+  /// Milliseconds allowed for the device to answer a request
+  pub request_timeout: Option<u32>,
+}
+
+impl TryFrom<ConnectionOptions> for ClientOptions {
+  type Error = Error;
+
+  fn try_from(options: ConnectionOptions) -> Result<Self> {
+    let mut client_options = ClientOptions::new(options.address).port(options.port);
+    if let Some(password) = options.password {
+      client_options = client_options.password(password);
+    }
+    if let Some(expected_name) = options.expected_name {
+      client_options = client_options.expected_name(expected_name);
+    }
+    if let Some(psk) = options.psk {
+      let psk = psk
+        .parse::<Psk>()
+        .map_err(|e| Error::new(Status::InvalidArg, e.to_string()))?;
+      client_options = client_options.psk(psk);
+    }
+    if let Some(client_info) = options.client_info {
+      client_options = client_options.client_info(client_info);
+    }
+    if let Some(keep_alive_duration) = options.keep_alive_duration {
+      client_options = client_options.keep_alive(Duration::from_secs(keep_alive_duration as u64));
+    }
+    if let Some(connect_timeout) = options.connect_timeout {
+      client_options =
+        client_options.connect_timeout(Duration::from_millis(connect_timeout as u64));
+    }
+    if let Some(request_timeout) = options.request_timeout {
+      client_options =
+        client_options.request_timeout(Duration::from_millis(request_timeout as u64));
+    }
+    Ok(client_options)
+  }
 }
 
 #[napi(object)]
@@ -36,16 +78,9 @@ pub struct Manager {
 impl Manager {
   #[napi(factory)]
   pub async fn connect(options: ConnectionOptions) -> Result<Manager> {
-    let manager = RustManager::new(
-      options.address,
-      options.port,
-      options.password,
-      options.expected_name,
-      options.psk,
-      options.client_info,
-      options.keep_alive_duration,
-    )
-    .await;
+    let manager = RustManager::new(options.try_into()?)
+      .await
+      .map_err(|e| Error::new(Status::GenericFailure, e.to_string()))?;
 
     Ok(Manager { inner: manager })
   }
@@ -65,8 +100,8 @@ impl Manager {
     self
       .inner
       .get_entities()
-      .into_iter()
-      .map(|(_, e)| match e {
+      .into_values()
+      .map(|e| match e {
         esphomeapi_manager::entity::Entity::Light(light) => entity::Entity::Light(light.key()),
         esphomeapi_manager::entity::Entity::Switch(switch) => entity::Entity::Switch(switch.key()),
         esphomeapi_manager::entity::Entity::Sensor() => entity::Entity::Sensor(0),
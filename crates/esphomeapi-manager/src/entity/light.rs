@@ -9,6 +9,7 @@ use esphomeapi::{
 };
 
 use super::{BaseEntity, StateError, StateResult};
+use crate::Result;
 
 #[derive(Clone)]
 pub struct Light {
@@ -42,13 +43,13 @@ impl Light {
     }
   }
 
-  pub fn is_on(&self) -> esphomeapi::Result<bool> {
+  pub fn is_on(&self) -> Result<bool> {
     let state = self.get_state()?;
 
     Ok(state.state)
   }
 
-  pub async fn turn_on(&self) -> esphomeapi::Result<()> {
+  pub async fn turn_on(&self) -> Result<()> {
     self
       .client
       .light_command(
@@ -66,10 +67,11 @@ impl Light {
         None,
         None,
       )
-      .await
+      .await?;
+    Ok(())
   }
 
-  pub async fn turn_off(&self) -> esphomeapi::Result<()> {
+  pub async fn turn_off(&self) -> Result<()> {
     self
       .client
       .light_command(
@@ -87,17 +89,18 @@ impl Light {
         None,
         None,
       )
-      .await
+      .await?;
+    Ok(())
   }
 
-  pub async fn toggle(&self) -> esphomeapi::Result<()> {
+  pub async fn toggle(&self) -> Result<()> {
     match self.is_on()? {
       true => self.turn_off().await,
       false => self.turn_on().await,
     }
   }
 
-  pub fn brightness(&self) -> esphomeapi::Result<f32> {
+  pub fn brightness(&self) -> Result<f32> {
     let state = self.get_state()?;
 
     Ok(state.brightness)
@@ -9,6 +9,7 @@ use esphomeapi::{
 };
 
 use super::{BaseEntity, StateError, StateResult};
+use crate::Result;
 
 #[derive(Clone)]
 pub struct Switch {
@@ -42,34 +43,36 @@ impl Switch {
     }
   }
 
-  pub fn is_on(&self) -> esphomeapi::Result<bool> {
+  pub fn is_on(&self) -> Result<bool> {
     let state = self.get_state()?;
 
     Ok(state.state)
   }
 
-  pub async fn turn_on(&self) -> esphomeapi::Result<()> {
+  pub async fn turn_on(&self) -> Result<()> {
     self
       .client
       .switch_command(self.info.entity_info.key, true)
-      .await
+      .await?;
+    Ok(())
   }
 
-  pub async fn turn_off(&self) -> esphomeapi::Result<()> {
+  pub async fn turn_off(&self) -> Result<()> {
     self
       .client
       .switch_command(self.info.entity_info.key, false)
-      .await
+      .await?;
+    Ok(())
   }
 
-  pub async fn toggle(&self) -> esphomeapi::Result<()> {
+  pub async fn toggle(&self) -> Result<()> {
     match self.is_on()? {
       true => self.turn_off().await,
       false => self.turn_on().await,
     }
   }
 
-  pub async fn set_state(&self, state: bool) -> esphomeapi::Result<()> {
+  pub async fn set_state(&self, state: bool) -> Result<()> {
     match state {
       true => self.turn_on().await,
       false => self.turn_off().await,
@@ -0,0 +1,41 @@
+use std::fmt;
+
+use crate::entity::StateError;
+
+#[derive(Debug)]
+pub enum Error {
+  Api(esphomeapi::Error),
+  State(StateError),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Api(e) => write!(f, "{}", e),
+      Self::State(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Api(e) => Some(e),
+      Self::State(e) => Some(e),
+    }
+  }
+}
+
+impl From<esphomeapi::Error> for Error {
+  fn from(error: esphomeapi::Error) -> Self {
+    Self::Api(error)
+  }
+}
+
+impl From<StateError> for Error {
+  fn from(error: StateError) -> Self {
+    Self::State(error)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
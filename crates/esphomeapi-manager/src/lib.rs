@@ -4,45 +4,31 @@ use std::{
 };
 
 pub mod entity;
+mod error;
 
 use entity::Entity;
 use esphomeapi::{
-  Client, Options as _, api,
-  model::{DeviceInfo, EntityInfo, EntityState, SUBCRIBE_STATES_RESPONSE_TYPES, UserService},
+  Client, ConnectionEvent, Options as _, api,
+  model::{DeviceInfo, EntityInfo, SUBCRIBE_STATES_RESPONSE_TYPES},
 };
+use tokio::sync::broadcast::error::RecvError;
 
+pub use error::{Error, Result};
 pub use esphomeapi::discovery::{ServiceInfo, discover};
+pub use esphomeapi::{ClientOptions, Psk};
 
 pub struct Manager {
   pub device_info: DeviceInfo,
   entities: HashMap<u32, Entity>,
-  states: Arc<RwLock<HashMap<u32, EntityState>>>,
-  services: HashMap<u32, UserService>,
 }
 
 impl Manager {
-  pub async fn new(
-    address: String,
-    port: u32,
-    password: Option<String>,
-    expected_name: Option<String>,
-    psk: Option<String>,
-    client_info: Option<String>,
-    keep_alive_duration: Option<u32>,
-  ) -> Manager {
-    let mut client = Client::new(
-      address,
-      port,
-      password,
-      expected_name,
-      psk,
-      client_info,
-      keep_alive_duration,
-    );
-
-    client.connect(true).await.unwrap();
-    let device_info = client.device_info().await.unwrap();
-    let (entities_response, services_response) = client.list_entities_services().await.unwrap();
+  pub async fn new(options: ClientOptions) -> Result<Manager> {
+    let mut client = Client::new(options);
+
+    client.connect(true).await?;
+    let device_info = client.device_info().await?;
+    let (entities_response, _) = client.list_entities_services().await?;
 
     let states = Arc::new(RwLock::new(HashMap::new()));
 
@@ -55,29 +41,52 @@ impl Manager {
 
     for msg_type in state_msg_types {
       let states = states.clone();
-      client.add_message_handler(
-        msg_type,
-        Box::new(move |_, msg| {
-          if msg.protobuf_type == api::CameraImageResponse::get_option_id() {
-            return Ok(());
-          }
-
-          if let Some(parser) = SUBCRIBE_STATES_RESPONSE_TYPES.get(&msg.protobuf_type) {
-            let state = parser(&msg.protobuf_data).unwrap();
-            states.write().unwrap().insert(state.key(), state);
-          }
-          Ok(())
-        }),
-        false,
-      );
+      client
+        .add_message_handler(
+          msg_type,
+          Box::new(move |_, msg| {
+            if msg.protobuf_type == api::CameraImageResponse::get_option_id() {
+              return Ok(());
+            }
+
+            if let Some(parser) = SUBCRIBE_STATES_RESPONSE_TYPES.get(&msg.protobuf_type) {
+              let state = parser(&msg.protobuf_data)?;
+              states.write().unwrap().insert(state.key(), state);
+            }
+            Ok(())
+          }),
+          false,
+        )
+        .detach();
     }
 
-    client.subscribe_states().await.unwrap();
+    client.subscribe_states().await?;
 
     let mut entities = HashMap::new();
 
     let client = Arc::new(client);
 
+    // Subscriptions don't survive a reconnect, so ask for the states again
+    // The task must not keep the client alive, dropping the manager disconnects it
+    let mut events = client.events();
+    let events_client = Arc::downgrade(&client);
+    tokio::spawn(async move {
+      loop {
+        match events.recv().await {
+          Ok(ConnectionEvent::Connected) => {
+            let Some(client) = events_client.upgrade() else {
+              break;
+            };
+            if let Err(e) = client.subscribe_states().await {
+              tracing::warn!(error = %e, "resubscribing to states failed");
+            }
+          }
+          Ok(_) | Err(RecvError::Lagged(_)) => {}
+          Err(RecvError::Closed) => break,
+        }
+      }
+    });
+
     for entity in entities_response {
       match entity {
         EntityInfo::Light(info) => {
@@ -92,18 +101,10 @@ impl Manager {
       }
     }
 
-    let mut services = HashMap::new();
-
-    for service in services_response {
-      services.insert(service.key, service);
-    }
-
-    Self {
+    Ok(Self {
       device_info,
       entities,
-      services,
-      states,
-    }
+    })
   }
 
   pub fn get_entities(&self) -> HashMap<u32, Entity> {
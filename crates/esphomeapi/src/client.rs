@@ -1,41 +1,61 @@
-use protobuf::{EnumOrUnknown, Message};
+use futures::Stream;
+use protobuf::{EnumOrUnknown, MessageFull};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  sync::{broadcast, watch},
+};
+use tokio_stream::StreamExt as _;
+use tracing::warn;
 
 use crate::{
-  connection::Callback,
+  connection::{
+    Callback, ConnectionEvent, ConnectionMetrics, ConnectionState, HandlerGuard, HandlerId,
+    ProtobufMessage, ReconnectEvent, Subscription, TypedSubscription,
+  },
   model::{
-    parse_user_service, ColorMode, DeviceInfo, EntityInfo, UserService,
+    parse_user_service, APIVersion, ClimateInfo, ClimatePreset, ClimateState, ColorMode,
+    CoverState, DeviceInfo, EntityInfo, LightInfo, UserService,
     LIST_ENTITIES_SERVICES_RESPONSE_TYPES,
   },
-  utils::Options as _,
+  utils::{message_ids_where, message_name, Options as _},
 };
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
-use crate::{connection::Connection, proto, Result};
+use crate::{connection::Connection, proto, ClientOptions, Error, Result};
+
+/// An entry of the entity listing of a device.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum ListEntitiesItem {
+  Entity(EntityInfo),
+  Service(UserService),
+  /// A `ListEntities*Response` this library can't parse yet
+  Unknown(ProtobufMessage),
+}
+
+impl ListEntitiesItem {
+  /// Parses a message of the listing, types without a parser are kept as they are.
+  fn parse(message: ProtobufMessage) -> Result<Self> {
+    if message.protobuf_type == proto::api::ListEntitiesServicesResponse::get_option_id() {
+      return Ok(ListEntitiesItem::Service(parse_user_service(
+        &message.protobuf_data,
+      )?));
+    }
+    match LIST_ENTITIES_SERVICES_RESPONSE_TYPES.get(&message.protobuf_type) {
+      Some(parser) => Ok(ListEntitiesItem::Entity(parser(&message.protobuf_data)?)),
+      None => Ok(ListEntitiesItem::Unknown(message)),
+    }
+  }
+}
 
 pub struct Client {
   connection: Connection,
 }
 
 impl Client {
-  pub fn new(
-    address: String,
-    port: u32,
-    password: Option<String>,
-    expected_name: Option<String>,
-    psk: Option<String>,
-    client_info: Option<String>,
-    keep_alive_duration: Option<u32>,
-  ) -> Self {
+  pub fn new(options: ClientOptions) -> Self {
     Self {
-      connection: Connection::new(
-        address,
-        port,
-        password,
-        expected_name,
-        psk,
-        client_info,
-        keep_alive_duration,
-      ),
+      connection: Connection::new(options),
     }
   }
 
@@ -43,74 +63,255 @@ impl Client {
     self.connection.connect(login).await
   }
 
-  pub async fn device_info(&self) -> Result<DeviceInfo> {
-    let message = proto::api::DeviceInfoRequest::default();
+  /// Runs the protocol over an already opened stream, see [`Connection::connect_with_stream`].
+  pub async fn connect_with_stream<S>(&mut self, stream: S, login: bool) -> Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+  {
+    self.connection.connect_with_stream(stream, login).await
+  }
+
+  /// Closes the connection, see [`Connection::disconnect`].
+  pub async fn disconnect(&self, force: bool) -> Result<()> {
+    self.connection.disconnect(force).await
+  }
+
+  /// Returns a receiver that is notified about every reconnect attempt.
+  pub fn reconnect_events(&self) -> broadcast::Receiver<ReconnectEvent> {
+    self.connection.reconnect_events()
+  }
+
+  /// Returns a receiver that is notified about every lifecycle change of the connection.
+  pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+    self.connection.events()
+  }
+
+  /// Returns a receiver that always holds the current state of the connection.
+  pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+    self.connection.watch_state()
+  }
+
+  pub fn state(&self) -> ConnectionState {
+    self.connection.state()
+  }
 
-    let response = self
+  pub fn is_connected(&self) -> bool {
+    self.connection.is_connected()
+  }
+
+  /// Queue and traffic counters of the connection, for monitoring.
+  pub fn metrics(&self) -> ConnectionMetrics {
+    self.connection.metrics()
+  }
+
+  /// Address of the device the current connection was established to.
+  pub fn peer_addr(&self) -> Option<SocketAddr> {
+    self.connection.peer_addr()
+  }
+
+  /// Major API version reported by the device, `None` until connected.
+  pub fn api_version_major(&self) -> Option<u32> {
+    self
       .connection
-      .send_message_await_response(
-        Box::new(message),
-        proto::api::DeviceInfoResponse::get_option_id(),
+      .hello_response()
+      .map(|hello| hello.api_version_major)
+  }
+
+  /// Minor API version reported by the device, `None` until connected.
+  pub fn api_version_minor(&self) -> Option<u32> {
+    self
+      .connection
+      .hello_response()
+      .map(|hello| hello.api_version_minor)
+  }
+
+  /// Free-form description of the device firmware, e.g. "ESPHome v1.10.0 on ESP8266".
+  pub fn server_info(&self) -> Option<String> {
+    self
+      .connection
+      .hello_response()
+      .map(|hello| hello.server_info)
+  }
+
+  /// API version reported by the device, `None` until connected.
+  pub fn api_version(&self) -> Option<APIVersion> {
+    self.connection.hello_response().map(|hello| {
+      APIVersion::new(
+        u8::try_from(hello.api_version_major).unwrap_or(u8::MAX),
+        u8::try_from(hello.api_version_minor).unwrap_or(u8::MAX),
       )
-      .await?;
+    })
+  }
 
-    let response = proto::api::DeviceInfoResponse::parse_from_bytes(&response.protobuf_data)?;
+  fn negotiated_api_version(&self) -> Result<APIVersion> {
+    self.api_version().ok_or(Error::NotConnected)
+  }
 
-    Ok(response.into())
+  /// Bluetooth proxy features of the device, translated from the legacy version on old firmwares.
+  pub fn bluetooth_proxy_feature_flags(&self, device_info: &DeviceInfo) -> Result<u32> {
+    Ok(device_info.bluetooth_proxy_feature_flags_compat(self.negotiated_api_version()?))
   }
 
-  pub async fn list_entities_services(&self) -> Result<(Vec<EntityInfo>, Vec<UserService>)> {
-    let message = proto::api::ListEntitiesRequest::new();
+  /// Voice assistant features of the device, translated from the legacy version on old firmwares.
+  pub fn voice_assistant_feature_flags(&self, device_info: &DeviceInfo) -> Result<u32> {
+    Ok(device_info.voice_assistant_feature_flags_compat(self.negotiated_api_version()?))
+  }
 
-    let entity_service_map = LIST_ENTITIES_SERVICES_RESPONSE_TYPES.clone();
-    let mut response_protobuf_types: Vec<u32> = entity_service_map.keys().cloned().collect();
-    // Add user defined services to the list of expected responses
-    response_protobuf_types.push(proto::api::ListEntitiesServicesResponse::get_option_id());
+  /// Color modes supported by the light, derived from the legacy flags on old firmwares.
+  pub fn light_supported_color_modes(&self, info: &LightInfo) -> Result<Vec<u8>> {
+    Ok(info.supported_color_modes_compat(self.negotiated_api_version()?))
+  }
+
+  /// Presets supported by the climate, derived from the legacy away flag on old firmwares.
+  pub fn climate_supported_presets(&self, info: &ClimateInfo) -> Result<Vec<ClimatePreset>> {
+    Ok(info.supported_presets_compat(self.negotiated_api_version()?))
+  }
+
+  /// Current preset of the climate, derived from the legacy away flag on old firmwares.
+  pub fn climate_preset(&self, state: &ClimateState) -> Result<ClimatePreset> {
+    Ok(state.preset_compat(self.negotiated_api_version()?))
+  }
+
+  /// Whether the cover is closed, using the legacy state on old firmwares.
+  pub fn cover_is_closed(&self, state: &CoverState) -> Result<bool> {
+    Ok(state.is_closed(self.negotiated_api_version()?))
+  }
+
+  pub async fn device_info(&self) -> Result<DeviceInfo> {
+    self
+      .device_info_timeout(self.connection.request_timeout())
+      .await
+  }
 
-    let response = self
+  /// Like [`Client::device_info`], waiting `timeout` instead of the configured request timeout.
+  pub async fn device_info_timeout(&self, timeout: Duration) -> Result<DeviceInfo> {
+    let response: proto::api::DeviceInfoResponse = self
       .connection
-      .send_message_await_until(
-        Box::new(message),
-        response_protobuf_types,
-        proto::api::ListEntitiesDoneResponse::get_option_id(),
-        Duration::from_secs(60),
-      )
+      .request_with_timeout(proto::api::DeviceInfoRequest::new(), timeout)
       .await?;
-    println!("Received list entities services response");
+
+    Ok(response.into())
+  }
+
+  /// Sends `request` and returns the parsed `Resp`, see [`Connection::request`].
+  pub async fn request<Req: MessageFull, Resp: MessageFull>(&self, request: Req) -> Result<Resp> {
+    self.connection.request(request).await
+  }
+
+  /// Sends `request` without waiting for an answer, see [`Connection::send`].
+  pub async fn send<Req: MessageFull>(&self, request: Req) -> Result<()> {
+    self.connection.send(request).await
+  }
+
+  pub async fn list_entities_services(&self) -> Result<(Vec<EntityInfo>, Vec<UserService>)> {
+    self
+      .list_entities_services_timeout(self.connection.request_timeout())
+      .await
+  }
+
+  /// Like [`Client::list_entities_services`], waiting at most `timeout` for each entity
+  /// instead of the configured request timeout.
+  ///
+  /// If the device stalls before `ListEntitiesDoneResponse`, fails with
+  /// [`Error::ListEntitiesTimeout`] holding everything listed until then. Entity types
+  /// this library can't parse are skipped.
+  pub async fn list_entities_services_timeout(
+    &self,
+    timeout: Duration,
+  ) -> Result<(Vec<EntityInfo>, Vec<UserService>)> {
+    let stream = self.stream_entities_services(timeout).await?;
+    let mut stream = std::pin::pin!(stream);
 
     let mut entities = Vec::new();
     let mut services = Vec::new();
-    for message in response {
-      if message.protobuf_type == proto::api::ListEntitiesServicesResponse::get_option_id() {
-        let parsed_service = parse_user_service(&message.protobuf_data)?;
-        services.push(parsed_service);
-      } else {
-        let parser = entity_service_map
-          .get(&message.protobuf_type)
-          .ok_or_else(|| format!("Unknown message type: {}", message.protobuf_type))?;
-        let parsed_message = parser(&message.protobuf_data)?;
-        entities.push(parsed_message);
+    while let Some(item) = stream.next().await {
+      match item {
+        Ok(ListEntitiesItem::Entity(entity)) => entities.push(entity),
+        Ok(ListEntitiesItem::Service(service)) => services.push(service),
+        Ok(ListEntitiesItem::Unknown(message)) => {
+          warn!(
+            message = message_name(message.protobuf_type),
+            "skipping unsupported entity type"
+          );
+        }
+        Err(Error::Timeout) => return Err(Error::ListEntitiesTimeout { entities, services }),
+        Err(e) => return Err(e),
       }
     }
 
     Ok((entities, services))
   }
 
+  /// Lists the entities and services of the device, yielding each one as soon as it is parsed.
+  ///
+  /// The stream ends after `ListEntitiesDoneResponse` or the first error. If the device
+  /// stalls for longer than the request timeout it yields [`Error::Timeout`].
+  pub async fn list_entities_services_stream(
+    &self,
+  ) -> Result<impl Stream<Item = Result<ListEntitiesItem>> + Send + 'static> {
+    self
+      .stream_entities_services(self.connection.request_timeout())
+      .await
+  }
+
+  async fn stream_entities_services(
+    &self,
+    timeout: Duration,
+  ) -> Result<impl Stream<Item = Result<ListEntitiesItem>> + Send + 'static> {
+    let message = proto::api::ListEntitiesRequest::new();
+    let done_protobuf_type = proto::api::ListEntitiesDoneResponse::get_option_id();
+    // Every ListEntities*Response, so types without a parser are reported instead of lost
+    let response_protobuf_types =
+      message_ids_where(|name| name.starts_with("ListEntities") && name.ends_with("Response"))
+        .into_iter()
+        .filter(|protobuf_type| *protobuf_type != done_protobuf_type)
+        .collect();
+
+    let messages = self
+      .connection
+      .send_message_stream_until(
+        Box::new(message),
+        response_protobuf_types,
+        done_protobuf_type,
+        timeout,
+      )
+      .await?;
+
+    Ok(messages.map(|message| ListEntitiesItem::parse(message?)))
+  }
+
+  /// Calls `callback` for messages of `msg_type`, see [`Connection::add_message_handler`].
   pub fn add_message_handler(
-    &mut self,
+    &self,
     msg_type: u32,
     callback: Callback,
     remove_after_call: bool,
-  ) {
+  ) -> HandlerGuard {
     self
       .connection
-      .add_message_handler(msg_type, callback, remove_after_call);
+      .add_message_handler(msg_type, callback, remove_after_call)
+  }
+
+  /// Removes a handler, returns `false` if it was already removed.
+  pub fn remove_message_handler(&self, id: HandlerId) -> bool {
+    self.connection.remove_message_handler(id)
+  }
+
+  /// Streams every message of `protobuf_types` received from now on, see [`Connection::subscribe`].
+  pub fn subscribe(&self, protobuf_types: &[u32]) -> Subscription {
+    self.connection.subscribe(protobuf_types)
   }
 
-  pub async fn subscribe_states(&mut self) -> Result<()> {
-    let message = proto::api::SubscribeStatesRequest::new();
-    self.connection.send_message(Box::new(message)).await?;
-    Ok(())
+  /// Streams the parsed messages of type `M` received from now on.
+  pub fn subscribe_typed<M: MessageFull>(&self) -> Result<TypedSubscription<M>> {
+    self.connection.subscribe_typed()
+  }
+
+  pub async fn subscribe_states(&self) -> Result<()> {
+    self
+      .connection
+      .send(proto::api::SubscribeStatesRequest::new())
+      .await
   }
 
   pub async fn switch_command(&self, key: u32, state: bool) -> Result<()> {
@@ -120,10 +321,10 @@ impl Client {
       ..Default::default()
     };
 
-    self.connection.send_message(Box::new(message)).await?;
-    Ok(())
+    self.connection.send(message).await
   }
 
+  #[allow(clippy::too_many_arguments)]
   pub async fn light_command(
     &self,
     key: u32,
@@ -171,7 +372,52 @@ impl Client {
       ..Default::default()
     };
 
-    self.connection.send_message(Box::new(message)).await?;
-    Ok(())
+    self.connection.send(message).await
+  }
+}
+
+impl Drop for Client {
+  fn drop(&mut self) {
+    self.connection.shutdown();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message<M: MessageFull>(message: M) -> ProtobufMessage {
+    ProtobufMessage {
+      protobuf_type: M::get_option_id(),
+      protobuf_data: message.write_to_bytes().unwrap(),
+    }
+  }
+
+  #[test]
+  fn parses_entities_and_services() {
+    let mut switch = proto::api::ListEntitiesSwitchResponse::new();
+    switch.key = 1;
+    assert!(matches!(
+      ListEntitiesItem::parse(message(switch)),
+      Ok(ListEntitiesItem::Entity(EntityInfo::Switch(info))) if info.entity_info.key == 1
+    ));
+
+    let mut service = proto::api::ListEntitiesServicesResponse::new();
+    service.key = 2;
+    assert!(matches!(
+      ListEntitiesItem::parse(message(service)),
+      Ok(ListEntitiesItem::Service(service)) if service.key == 2
+    ));
+  }
+
+  #[test]
+  fn reports_types_without_a_parser() {
+    // Every ListEntities*Response has a parser today, another type stands in for a new one
+    let state = message(proto::api::SwitchStateResponse::new());
+    assert!(matches!(
+      ListEntitiesItem::parse(state),
+      Ok(ListEntitiesItem::Unknown(message))
+        if message.protobuf_type == proto::api::SwitchStateResponse::get_option_id()
+    ));
   }
 }
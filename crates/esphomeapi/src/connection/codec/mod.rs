@@ -1,15 +1,15 @@
 mod noise;
 mod plain;
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use bytes::Bytes;
-pub use noise::Noise;
-pub use plain::Plain;
-use tokio::sync::{mpsc, oneshot};
+use bytes::{Bytes, BytesMut};
+use noise::Noise;
+pub use noise::Psk;
+use plain::Plain;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{Connection, Result as EspResult};
+use crate::{Connection, Error, Result as EspResult};
 
 pub type Callback =
   Box<dyn Fn(Arc<RwLock<Connection>>, ProtobufMessage) -> EspResult<()> + Send + Sync + 'static>;
@@ -21,55 +21,10 @@ pub struct ProtobufMessage {
   pub protobuf_data: Vec<u8>,
 }
 
+#[derive(Debug)]
 pub enum EspHomeMessageType {
-  Response {
-    protobuf_message: ProtobufMessage,
-  },
-  Request {
-    protobuf_message: ProtobufMessage,
-  },
-  RequestWithAwait {
-    protobuf_message: ProtobufMessage,
-    response_protobuf_type: u32,
-    tx: oneshot::Sender<ProtobufMessage>,
-  },
-  RequestWithAwaitMultipleUntil {
-    protobuf_message: ProtobufMessage,
-    response_protobuf_types: Vec<u32>,
-    until_protobuf_type: u32,
-    tx: mpsc::Sender<ProtobufMessage>,
-  },
-}
-
-impl std::fmt::Debug for EspHomeMessageType {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    match self {
-      EspHomeMessageType::Response { protobuf_message } => f
-        .debug_struct("Response")
-        .field("protobuf_type", &protobuf_message.protobuf_type)
-        .field("protobuf_data", &protobuf_message.protobuf_data)
-        .finish(),
-      EspHomeMessageType::Request { protobuf_message } => f
-        .debug_struct("Request")
-        .field("protobuf_type", &protobuf_message.protobuf_type)
-        .field("protobuf_data", &protobuf_message.protobuf_data)
-        .finish(),
-      EspHomeMessageType::RequestWithAwait {
-        protobuf_message, ..
-      } => f
-        .debug_struct("RequestWithAwait")
-        .field("protobuf_type", &protobuf_message.protobuf_type)
-        .field("protobuf_data", &protobuf_message.protobuf_data)
-        .finish(),
-      EspHomeMessageType::RequestWithAwaitMultipleUntil {
-        protobuf_message, ..
-      } => f
-        .debug_struct("RequestWithAwaitMultipleUntil")
-        .field("protobuf_type", &protobuf_message.protobuf_type)
-        .field("protobuf_data", &protobuf_message.protobuf_data)
-        .finish(),
-    }
-  }
+  Response { protobuf_message: ProtobufMessage },
+  Request { protobuf_message: ProtobufMessage },
 }
 
 #[derive(Debug)]
@@ -83,13 +38,14 @@ impl EspHomeMessage {
   pub fn get_protobuf_message(&self) -> &ProtobufMessage {
     match &self.message_type {
       EspHomeMessageType::Response { protobuf_message }
-      | EspHomeMessageType::Request { protobuf_message }
-      | EspHomeMessageType::RequestWithAwait {
-        protobuf_message, ..
-      }
-      | EspHomeMessageType::RequestWithAwaitMultipleUntil {
-        protobuf_message, ..
-      } => protobuf_message,
+      | EspHomeMessageType::Request { protobuf_message } => protobuf_message,
+    }
+  }
+
+  pub fn into_protobuf_message(self) -> ProtobufMessage {
+    match self.message_type {
+      EspHomeMessageType::Response { protobuf_message }
+      | EspHomeMessageType::Request { protobuf_message } => protobuf_message,
     }
   }
 
@@ -115,103 +71,162 @@ impl EspHomeMessage {
       },
     }
   }
+}
 
-  pub fn new_request_with_await(
-    protobuf_type: u32,
-    protobuf_data: Vec<u8>,
-    response_protobuf_type: u32,
-    tx: oneshot::Sender<ProtobufMessage>,
-  ) -> Self {
-    EspHomeMessage {
-      message_type: EspHomeMessageType::RequestWithAwait {
-        protobuf_message: ProtobufMessage {
-          protobuf_type,
-          protobuf_data,
-        },
-        response_protobuf_type,
-        tx,
-      },
-    }
+/// Turns messages into frames on the wire and back.
+///
+/// [`EspHomeCodec`] speaks the native API framings, other implementations can wrap it or
+/// replace it and are handed to the connection with
+/// [`ClientOptions::codec`](crate::ClientOptions::codec). A fresh codec is made for
+/// every session, the reader and the writer of a session share it.
+pub trait FrameCodec:
+  Encoder<EspHomeMessage, Error = Error> + Decoder<Item = EspHomeMessage, Error = Error> + Send
+{
+  /// Frame sent before anything else, e.g. to start a handshake.
+  ///
+  /// If there is one, the session feeds the device's answers to the decoder until
+  /// [`FrameCodec::handshake_completed`] returns true, and sends the `HelloRequest` after.
+  /// The decoder consumes the handshake frames without yielding messages.
+  fn get_handshake_frame(&mut self) -> Result<Option<Bytes>, Error> {
+    Ok(None)
   }
 
-  pub fn new_request_with_await_multiple_until(
-    protobuf_type: u32,
-    protobuf_data: Vec<u8>,
-    response_protobuf_types: Vec<u32>,
-    until_protobuf_type: u32,
-    tx: mpsc::Sender<ProtobufMessage>,
-  ) -> Self {
-    EspHomeMessage {
-      message_type: EspHomeMessageType::RequestWithAwaitMultipleUntil {
-        protobuf_message: ProtobufMessage {
-          protobuf_type,
-          protobuf_data,
-        },
-        response_protobuf_types,
-        until_protobuf_type,
-        tx,
-      },
-    }
+  /// Whether the handshake started with the handshake frame completed.
+  fn handshake_completed(&self) -> bool {
+    true
+  }
+
+  /// Checks the first byte the device answered with, `None` if it closed the connection
+  /// instead, so a device using another framing is reported as such.
+  fn check_framing(&self, _first_byte: Option<u8>) -> Result<(), Error> {
+    Ok(())
   }
+
+  /// Called once the session ended.
+  fn close(&mut self) {}
 }
 
-pub trait FrameCodec:
-  Encoder<EspHomeMessage, Error = std::io::Error>
-  + Decoder<Item = EspHomeMessage, Error = std::io::Error>
-{
-  fn parse_frame(&self, src: &mut bytes::BytesMut) -> Result<(u8, u8), std::io::Error>;
-  fn get_handshake_frame(&mut self) -> Option<Bytes>;
-  fn close(&mut self);
+/// Makes the codec of each session, see [`ClientOptions::codec`](crate::ClientOptions::codec).
+pub type CodecFactory = Arc<dyn Fn() -> Box<dyn FrameCodec> + Send + Sync + 'static>;
+
+enum Framing {
+  Plain(Plain),
+  Noise(Box<Noise>),
 }
 
-#[derive(Clone)]
-pub enum EspHomeCodec {
-  Noise(Arc<RwLock<Noise>>),
-  Plain(Arc<RwLock<Plain>>),
+/// The framings of the native API, plaintext or encrypted with a noise pre-shared key.
+pub struct EspHomeCodec {
+  framing: Framing,
+}
+
+impl EspHomeCodec {
+  /// Plaintext framing, for devices without an encryption key.
+  pub fn plaintext() -> Self {
+    EspHomeCodec {
+      framing: Framing::Plain(Plain::new()),
+    }
+  }
+
+  /// Encrypted framing, the device has to report `expected_server_name` if it is set.
+  pub fn noise(psk: &Psk, expected_server_name: Option<String>) -> Self {
+    EspHomeCodec {
+      framing: Framing::Noise(Box::new(Noise::new(psk, expected_server_name))),
+    }
+  }
 }
 
 impl FrameCodec for EspHomeCodec {
-  fn parse_frame(&self, src: &mut bytes::BytesMut) -> Result<(u8, u8), std::io::Error> {
-    match self {
-      EspHomeCodec::Noise(codec) => codec.read().unwrap().parse_frame(src),
-      EspHomeCodec::Plain(codec) => codec.read().unwrap().parse_frame(src),
+  fn get_handshake_frame(&mut self) -> Result<Option<Bytes>, Error> {
+    match &mut self.framing {
+      Framing::Noise(codec) => codec.get_handshake_frame(),
+      Framing::Plain(codec) => codec.get_handshake_frame(),
+    }
+  }
+
+  fn handshake_completed(&self) -> bool {
+    match &self.framing {
+      Framing::Noise(codec) => codec.handshake_completed(),
+      Framing::Plain(codec) => codec.handshake_completed(),
     }
   }
 
-  fn get_handshake_frame(&mut self) -> Option<Bytes> {
-    match self {
-      EspHomeCodec::Noise(codec) => codec.write().unwrap().get_handshake_frame(),
-      EspHomeCodec::Plain(codec) => codec.write().unwrap().get_handshake_frame(),
+  fn check_framing(&self, first_byte: Option<u8>) -> Result<(), Error> {
+    match &self.framing {
+      Framing::Noise(codec) => codec.check_framing(first_byte),
+      Framing::Plain(codec) => codec.check_framing(first_byte),
     }
   }
 
   fn close(&mut self) {
-    match self {
-      EspHomeCodec::Noise(codec) => codec.write().unwrap().close(),
-      EspHomeCodec::Plain(codec) => codec.write().unwrap().close(),
+    match &mut self.framing {
+      Framing::Noise(codec) => codec.close(),
+      Framing::Plain(codec) => codec.close(),
     }
   }
 }
 
 impl Encoder<EspHomeMessage> for EspHomeCodec {
-  type Error = std::io::Error;
+  type Error = Error;
 
-  fn encode(&mut self, item: EspHomeMessage, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-    match self {
-      EspHomeCodec::Noise(codec) => codec.write().unwrap().encode(item, dst),
-      EspHomeCodec::Plain(codec) => codec.write().unwrap().encode(item, dst),
+  fn encode(&mut self, item: EspHomeMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    match &mut self.framing {
+      Framing::Noise(codec) => codec.encode(item, dst),
+      Framing::Plain(codec) => codec.encode(item, dst),
     }
   }
 }
 
 impl Decoder for EspHomeCodec {
   type Item = EspHomeMessage;
-  type Error = std::io::Error;
+  type Error = Error;
 
-  fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-    match self {
-      EspHomeCodec::Noise(codec) => codec.write().unwrap().decode(src),
-      EspHomeCodec::Plain(codec) => codec.write().unwrap().decode(src),
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    match &mut self.framing {
+      Framing::Noise(codec) => codec.decode(src),
+      Framing::Plain(codec) => codec.decode(src),
     }
   }
 }
+
+/// Hands one codec to both the reader and the writer of a session.
+#[derive(Clone)]
+pub(crate) struct SharedCodec(Arc<Mutex<Box<dyn FrameCodec>>>);
+
+impl SharedCodec {
+  pub fn new(codec: Box<dyn FrameCodec>) -> Self {
+    SharedCodec(Arc::new(Mutex::new(codec)))
+  }
+
+  pub fn get_handshake_frame(&self) -> Result<Option<Bytes>, Error> {
+    self.0.lock().unwrap().get_handshake_frame()
+  }
+
+  pub fn handshake_completed(&self) -> bool {
+    self.0.lock().unwrap().handshake_completed()
+  }
+
+  pub fn check_framing(&self, first_byte: Option<u8>) -> Result<(), Error> {
+    self.0.lock().unwrap().check_framing(first_byte)
+  }
+
+  pub fn close(&self) {
+    self.0.lock().unwrap().close()
+  }
+}
+
+impl Encoder<EspHomeMessage> for SharedCodec {
+  type Error = Error;
+
+  fn encode(&mut self, item: EspHomeMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    self.0.lock().unwrap().encode(item, dst)
+  }
+}
+
+impl Decoder for SharedCodec {
+  type Item = EspHomeMessage;
+  type Error = Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    self.0.lock().unwrap().decode(src)
+  }
+}
@@ -1,4 +1,4 @@
-use std::io::Error;
+use std::{fmt, str::FromStr};
 
 use base64::prelude::*;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
@@ -7,9 +7,60 @@ use noise_rust_crypto::{ChaCha20Poly1305, Sha256, X25519};
 use tokio_util::codec::{Decoder, Encoder};
 
 use super::{EspHomeMessage, FrameCodec};
+use crate::Error;
+
+static PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+static HELLO: &[u8] = &[0x01, 0x00, 0x00];
+
+/// Preamble and 16-bit big endian length in front of every frame
+const HEADER_LEN: usize = 3;
+/// Largest frame payload the length field can describe
+const MAX_FRAME_LEN: usize = u16::MAX as usize;
+/// Message type and data length in front of the data of an encrypted frame
+const DATA_HEADER_LEN: usize = 4;
+/// Authentication tag appended by ChaCha20-Poly1305
+const TAG_LEN: usize = 16;
+
+/// Reason the device gives for rejecting a handshake made with another pre-shared key
+static MAC_FAILURE: &str = "Handshake MAC failure";
+
+/// Pre-shared key of a device using the encrypted transport, the `api: encryption: key:`
+/// of its configuration.
+///
+/// ```
+/// # use esphomeapi::Psk;
+/// let psk: Psk = "px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA=".parse().unwrap();
+/// assert!("too short".parse::<Psk>().is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+  /// Decodes a base64 encoded 32 byte key.
+  pub fn from_base64(psk: &str) -> Result<Self, Error> {
+    let decoded = BASE64_STANDARD
+      .decode(psk.trim().as_bytes())
+      .map_err(|e| Error::InvalidPsk(e.to_string()))?;
+    let key = <[u8; 32]>::try_from(decoded.as_slice())
+      .map_err(|_| Error::InvalidPsk(format!("expected 32 bytes, got {}", decoded.len())))?;
+    Ok(Psk(key))
+  }
+}
+
+impl FromStr for Psk {
+  type Err = Error;
 
-static PROLOGUE: &'static [u8] = b"NoiseAPIInit\x00\x00";
-static HELLO: &'static [u8] = &[0x01, 0x00, 0x00];
+  fn from_str(psk: &str) -> Result<Self, Self::Err> {
+    Psk::from_base64(psk)
+  }
+}
+
+// Keeps the key out of logs
+impl fmt::Debug for Psk {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("Psk(..)")
+  }
+}
 
 #[derive(PartialEq, Debug, Clone)]
 enum NoiseState {
@@ -29,11 +80,10 @@ pub struct Noise {
 }
 
 impl Noise {
-  pub fn new(psk: String, expected_server_name: Option<String>) -> Self {
-    let base64_psk = BASE64_STANDARD.decode(psk.as_bytes()).unwrap();
+  pub fn new(psk: &Psk, expected_server_name: Option<String>) -> Self {
     let mut initiator =
       HandshakeState::new(noise_nn_psk0(), true, PROLOGUE, None, None, None, None);
-    initiator.push_psk(base64_psk.as_slice());
+    initiator.push_psk(&psk.0);
 
     Noise {
       state: NoiseState::Hello,
@@ -43,51 +93,59 @@ impl Noise {
       encoder: None,
     }
   }
+
+  /// Reads the header at the start of `src` without consuming it.
+  ///
+  /// Returns the payload length, or `None` while the header is incomplete.
+  fn parse_frame(src: &[u8]) -> Result<Option<usize>, Error> {
+    if src.len() < HEADER_LEN {
+      return Ok(None);
+    }
+    match src[0] {
+      0x01 => {}
+      // A plaintext frame, the device answered the hello without encryption
+      0x00 => return Err(Error::EncryptionNotSupported),
+      _ => return Err(Error::Protocol("Invalid preamble".to_string())),
+    }
+    Ok(Some(u16::from_be_bytes([src[1], src[2]]) as usize))
+  }
 }
 
 impl FrameCodec for Noise {
-  fn get_handshake_frame(&mut self) -> Option<Bytes> {
+  fn get_handshake_frame(&mut self) -> Result<Option<Bytes>, Error> {
     let buffer = self
       .initiator
       .as_mut()
-      .unwrap()
+      .ok_or_else(|| Error::HandshakeFailed("handshake already completed".to_string()))?
       .write_message_vec(&[])
-      .unwrap();
-    let len = buffer.len() + 1;
-    let header = [0x01, (len.checked_shr(8).unwrap_or(0)) as u8, len as u8].to_vec();
+      .map_err(|e| Error::HandshakeFailed(e.to_string()))?;
+    let len = u16::try_from(buffer.len() + 1)
+      .map_err(|_| Error::HandshakeFailed("handshake message too large".to_string()))?;
 
-    let mut frame = BytesMut::with_capacity(1024);
+    let mut frame = BytesMut::with_capacity(HELLO.len() + HEADER_LEN + len as usize);
     frame.extend_from_slice(HELLO);
-    frame.extend_from_slice(&header);
+    frame.put_u8(0x01);
+    frame.put_u16(len);
     frame.put_u8(0);
     frame.extend_from_slice(&buffer);
 
-    Some(frame.freeze())
+    Ok(Some(frame.freeze()))
   }
 
-  fn parse_frame(&self, src: &mut bytes::BytesMut) -> Result<(u8, u8), Error> {
-    let header = &src[..3];
-
-    let preamble = header[0];
-    if preamble != 0x01 {
-      return Err(Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Invalid preamble",
-      ));
-    }
-
-    let msg_size_high = header[1];
-    let msg_size_low = header[2];
+  fn handshake_completed(&self) -> bool {
+    self.state == NoiseState::Ready
+  }
 
-    src.advance(3);
-    if src.len() < (msg_size_high as usize).checked_shl(8).unwrap_or(0) | msg_size_low as usize {
-      return Err(Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Invalid message size",
-      ));
+  fn check_framing(&self, first_byte: Option<u8>) -> Result<(), Error> {
+    match first_byte {
+      // A plaintext frame, the device answered the hello without encryption
+      Some(0x00) => Err(Error::EncryptionNotSupported),
+      // Says nothing about the framing, e.g. the device is rebooting or out of connections
+      None => Err(Error::HandshakeFailed(
+        "the device closed the connection".to_string(),
+      )),
+      _ => Ok(()),
     }
-
-    Ok((msg_size_high, msg_size_low))
   }
 
   fn close(&mut self) {
@@ -97,147 +155,417 @@ impl FrameCodec for Noise {
 
 impl Decoder for Noise {
   type Item = EspHomeMessage;
-  type Error = std::io::Error;
+  type Error = Error;
 
   fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-    if src.is_empty() {
+    let Some(msg_len) = Self::parse_frame(src)? else {
       return Ok(None);
-    }
+    };
 
-    let (msg_len_high, msg_len_low) = self.parse_frame(src)?;
-    let msg_len = (msg_len_high as usize).checked_shl(8).unwrap_or(0) | msg_len_low as usize;
+    // Wait until the whole frame arrived
+    if src.len() < HEADER_LEN + msg_len {
+      src.reserve(HEADER_LEN + msg_len - src.len());
+      return Ok(None);
+    }
 
+    src.advance(HEADER_LEN);
     let mut msg = src.split_to(msg_len);
 
     match self.state {
       NoiseState::Hello => {
-        let chosen_proto = msg[0];
-        if chosen_proto != 0x01 {
-          return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid protocol",
-          ));
+        let chosen_proto = msg.first().copied();
+        if chosen_proto != Some(0x01) {
+          return Err(Error::Protocol("Invalid protocol".to_string()));
         }
 
         let server_name_i = msg.iter().skip(1).position(|&x| x == 0x00);
 
-        match server_name_i {
-          Some(server_name_i) => {
-            // server name found, this extension was added in 2022.2
-            let server_name = msg
-              .iter()
-              .skip(1)
-              .take(server_name_i)
-              .copied()
-              .collect::<Vec<u8>>();
-            let server_name = String::from_utf8(server_name).unwrap();
-
-            if let Some(expected_server_name) = &self.expected_server_name {
-              if server_name != *expected_server_name {
-                return Err(Error::new(
-                  std::io::ErrorKind::InvalidData,
-                  "Invalid server name",
-                ));
-              }
+        // server name found, this extension was added in 2022.2
+        if let Some(server_name_i) = server_name_i {
+          let server_name = msg
+            .iter()
+            .skip(1)
+            .take(server_name_i)
+            .copied()
+            .collect::<Vec<u8>>();
+          let server_name = String::from_utf8(server_name)
+            .map_err(|_| Error::Protocol("Invalid server name".to_string()))?;
+
+          if let Some(expected_server_name) = &self.expected_server_name {
+            if server_name != *expected_server_name {
+              return Err(Error::ServerNameMismatch {
+                expected: expected_server_name.clone(),
+                received: server_name,
+              });
             }
           }
-          None => (), // server name not found
         }
         self.state = NoiseState::Handshake;
       }
       NoiseState::Handshake => {
-        if msg[0] != 0x00 {
-          return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid preamble",
-          ));
+        match msg.first() {
+          Some(0x00) => {}
+          // The device rejected the handshake, the rest of the frame explains why
+          Some(_) => {
+            let reason = String::from_utf8_lossy(&msg[1..]);
+            let reason = reason.trim_end_matches('\0');
+            if reason == MAC_FAILURE {
+              return Err(Error::PskRejected);
+            }
+            return Err(Error::HandshakeFailed(reason.to_string()));
+          }
+          None => return Err(Error::Protocol("Invalid preamble".to_string())),
         }
         msg.advance(1);
 
-        let mut handshake_state = self.initiator.take().unwrap();
-        handshake_state.read_message_vec(&msg).unwrap();
+        let mut handshake_state = self
+          .initiator
+          .take()
+          .ok_or_else(|| Error::HandshakeFailed("handshake already completed".to_string()))?;
+        handshake_state
+          .read_message_vec(&msg)
+          .map_err(|e| Error::HandshakeFailed(e.to_string()))?;
 
         if handshake_state.completed() {
           let (encoder, decoder) = handshake_state.get_ciphers();
           self.encoder = Some(encoder);
           self.decoder = Some(decoder);
           self.state = NoiseState::Ready;
-          return Ok(Some(EspHomeMessage::new_response(
-            0,
-            "Handshake completed".as_bytes().to_vec(),
-          )));
         } else {
           self.initiator = Some(handshake_state);
         }
       }
       NoiseState::Ready => {
-        if self.decoder.is_none() {
-          return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Decoder not initialized",
-          ));
+        let Some(decoder) = self.decoder.as_mut() else {
+          return Err(Error::Protocol("Decoder not initialized".to_string()));
+        };
+        let buffer = decoder
+          .decrypt_vec(&msg)
+          .map_err(|_| Error::Protocol("Failed to decrypt frame".to_string()))?;
+        if buffer.len() < DATA_HEADER_LEN {
+          return Err(Error::Protocol("Frame too short".to_string()));
         }
-        let buffer = self.decoder.as_mut().unwrap().decrypt_vec(&msg).unwrap();
 
         // Message layout is
         // 2 bytes: message type
         // 2 bytes: message length
         // N bytes: message data
-        let msg_type_high = buffer[0] as u32;
-        let msg_type_low = buffer[1] as u32;
+        let msg_type = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let data_len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        if data_len != buffer.len() - DATA_HEADER_LEN {
+          return Err(Error::Protocol("Invalid message length".to_string()));
+        }
 
         return Ok(Some(EspHomeMessage::new_response(
-          msg_type_high.checked_shr(8).unwrap_or(0) | msg_type_low,
-          buffer[4..].to_vec(),
+          msg_type as u32,
+          buffer[DATA_HEADER_LEN..].to_vec(),
         )));
       }
-      NoiseState::Closed => {
-        return Err(Error::new(
-          std::io::ErrorKind::InvalidData,
-          "Connection closed",
-        ))
-      }
+      NoiseState::Closed => return Err(Error::NotConnected),
     }
 
-    Ok(None)
+    // The next frame may have arrived in the same read, the reader only calls again
+    // once it read more bytes
+    self.decode(src)
   }
 }
 
 impl Encoder<EspHomeMessage> for Noise {
-  type Error = std::io::Error;
+  type Error = Error;
 
   fn encode(&mut self, item: EspHomeMessage, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-    if self.state != NoiseState::Ready || self.encoder.is_none() {
-      return Err(Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Encoder not initialized",
-      ));
+    if self.state != NoiseState::Ready {
+      return Err(Error::Protocol("Encoder not initialized".to_string()));
     }
-
-    let mut buffer = BytesMut::new();
+    let Some(encoder) = self.encoder.as_mut() else {
+      return Err(Error::Protocol("Encoder not initialized".to_string()));
+    };
 
     let message = item.get_protobuf_message();
+    let msg_type = u16::try_from(message.protobuf_type).map_err(|_| {
+      Error::Protocol(format!(
+        "Message type {} doesn't fit a frame",
+        message.protobuf_type
+      ))
+    })?;
+    // Checked before encrypting, so a rejected message doesn't advance the nonce
+    let data_len = message.protobuf_data.len();
+    if DATA_HEADER_LEN + data_len + TAG_LEN > MAX_FRAME_LEN {
+      return Err(Error::Protocol(format!(
+        "Message of {data_len} bytes doesn't fit a frame"
+      )));
+    }
 
-    let data_len = message.protobuf_data.len() as u8;
-    let data_header = [
-      (message.protobuf_type.checked_shr(8).unwrap_or(0)) as u8,
-      message.protobuf_type as u8,
-      (data_len.checked_shr(8).unwrap_or(0)) as u8,
-      data_len as u8,
-    ]
-    .to_vec();
-    buffer.extend_from_slice(&data_header);
+    let mut buffer = Vec::with_capacity(DATA_HEADER_LEN + data_len);
+    buffer.extend_from_slice(&msg_type.to_be_bytes());
+    buffer.extend_from_slice(&(data_len as u16).to_be_bytes());
     buffer.extend_from_slice(&message.protobuf_data);
 
-    let mut frame = BytesMut::zeroed(data_header.len() + message.protobuf_data.len() + 16);
+    let frame = encoder.encrypt_vec(&buffer);
 
-    self.encoder.as_mut().unwrap().encrypt(&buffer, &mut frame);
-    let len = frame.len();
-    let header = [0x01, (len.checked_shr(8).unwrap_or(0)) as u8, len as u8].to_vec();
-
-    dst.extend_from_slice(&header);
+    dst.reserve(HEADER_LEN + frame.len());
+    dst.put_u8(0x01);
+    dst.put_u16(frame.len() as u16);
     dst.extend_from_slice(&frame);
 
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use noise_protocol::U8Array;
+
+  use super::*;
+
+  const PSK: &str = "px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA=";
+
+  type Responder = HandshakeState<X25519, ChaCha20Poly1305, Sha256>;
+
+  fn frame(payload: &[u8]) -> BytesMut {
+    let mut frame = BytesMut::new();
+    frame.put_u8(0x01);
+    frame.put_u16(payload.len() as u16);
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  /// Completes the handshake against a device, returns the device's ciphers for
+  /// decrypting and encrypting.
+  fn handshake(
+    codec: &mut Noise,
+  ) -> (CipherState<ChaCha20Poly1305>, CipherState<ChaCha20Poly1305>) {
+    let hello = codec.get_handshake_frame().unwrap().unwrap();
+    let mut responder: Responder =
+      HandshakeState::new(noise_nn_psk0(), false, PROLOGUE, None, None, None, None);
+    responder.push_psk(&BASE64_STANDARD.decode(PSK).unwrap());
+    responder.read_message_vec(&hello[7..]).unwrap();
+
+    let mut server_hello = frame(b"\x01device\x00");
+    assert!(codec.decode(&mut server_hello).unwrap().is_none());
+
+    let mut reply = vec![0x00];
+    reply.extend(responder.write_message_vec(&[]).unwrap());
+    assert!(codec.decode(&mut frame(&reply)).unwrap().is_none());
+    assert!(codec.handshake_completed());
+
+    responder.get_ciphers()
+  }
+
+  #[test]
+  fn decodes_server_hello_and_reply_from_one_read() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let hello = codec.get_handshake_frame().unwrap().unwrap();
+    let mut responder: Responder =
+      HandshakeState::new(noise_nn_psk0(), false, PROLOGUE, None, None, None, None);
+    responder.push_psk(&BASE64_STANDARD.decode(PSK).unwrap());
+    responder.read_message_vec(&hello[7..]).unwrap();
+
+    let mut reply = vec![0x00];
+    reply.extend(responder.write_message_vec(&[]).unwrap());
+    let mut src = frame(b"\x01device\x00");
+    src.extend_from_slice(&frame(&reply));
+
+    assert!(codec.decode(&mut src).unwrap().is_none());
+    assert!(codec.handshake_completed());
+    assert!(src.is_empty());
+  }
+
+  /// Frames of a session with fixed ephemeral keys, 0x01..=0x20 for the client and
+  /// 0x21..=0x40 for the device, computed with a separate NNpsk0 implementation.
+  mod vectors {
+    pub const CLIENT_HELLO: &[u8] = &[
+      0x01, 0x00, 0x00, 0x01, 0x00, 0x31, 0x00, 0x07, 0xa3, 0x7c, 0xbc, 0x14, 0x20, 0x93, 0xc8,
+      0xb7, 0x55, 0xdc, 0x1b, 0x10, 0xe8, 0x6c, 0xb4, 0x26, 0x37, 0x4a, 0xd1, 0x6a, 0xa8, 0x53,
+      0xed, 0x0b, 0xdf, 0xc0, 0xb2, 0xb8, 0x6d, 0x1c, 0x7c, 0x93, 0xf7, 0xfe, 0x60, 0x2d, 0x01,
+      0x9d, 0x77, 0xa7, 0x6a, 0x9b, 0x1f, 0x63, 0x60, 0x5b, 0xd3,
+    ];
+    // Server hello with the name "device" and the handshake reply, arriving in one read
+    pub const DEVICE_HANDSHAKE: &[u8] = &[
+      0x01, 0x00, 0x08, 0x01, 0x64, 0x65, 0x76, 0x69, 0x63, 0x65, 0x00, 0x01, 0x00, 0x31, 0x00,
+      0x58, 0x69, 0xaf, 0xf4, 0x50, 0x54, 0x97, 0x32, 0xcb, 0xaa, 0xed, 0x5e, 0x5d, 0xf9, 0xb3,
+      0x0a, 0x6d, 0xa3, 0x1c, 0xb0, 0xe5, 0x74, 0x2b, 0xad, 0x5a, 0xd4, 0xa1, 0xa7, 0x68, 0xf1,
+      0xa6, 0x7b, 0x26, 0x00, 0x90, 0x2f, 0x4f, 0xba, 0x2c, 0x97, 0x49, 0x1a, 0xdb, 0x06, 0xab,
+      0xfd, 0x4a, 0xf1,
+    ];
+    // Type 0x0123 with the data "ping"
+    pub const CLIENT_FRAME: &[u8] = &[
+      0x01, 0x00, 0x18, 0x4e, 0x19, 0xae, 0x88, 0x16, 0x35, 0xc6, 0x34, 0x9b, 0xee, 0x8c, 0x08,
+      0xae, 0x6b, 0x4e, 0x19, 0x53, 0x23, 0x84, 0x79, 0xc6, 0xfa, 0x7e, 0x3d,
+    ];
+    // Type 0x0456 with the data "pong"
+    pub const DEVICE_FRAME: &[u8] = &[
+      0x01, 0x00, 0x18, 0x03, 0x63, 0x7b, 0x0b, 0x90, 0x24, 0xd2, 0xa0, 0xdc, 0x54, 0x1f, 0x84,
+      0xd1, 0x5f, 0x5c, 0x31, 0xf6, 0xca, 0x12, 0x24, 0x14, 0x31, 0xc8, 0x71,
+    ];
+  }
+
+  #[test]
+  fn matches_known_vectors() {
+    let ephemeral = (1..=32).collect::<Vec<u8>>();
+    let mut initiator = HandshakeState::new(
+      noise_nn_psk0(),
+      true,
+      PROLOGUE,
+      None,
+      Some(U8Array::from_slice(&ephemeral)),
+      None,
+      None,
+    );
+    initiator.push_psk(&BASE64_STANDARD.decode(PSK).unwrap());
+    let mut codec = Noise::new(&PSK.parse().unwrap(), Some("device".to_string()));
+    codec.initiator = Some(initiator);
+
+    let hello = codec.get_handshake_frame().unwrap().unwrap();
+    assert_eq!(&hello[..], vectors::CLIENT_HELLO);
+
+    let mut src = BytesMut::from(vectors::DEVICE_HANDSHAKE);
+    assert!(codec.decode(&mut src).unwrap().is_none());
+    assert!(codec.handshake_completed());
+
+    let mut dst = BytesMut::new();
+    codec
+      .encode(
+        EspHomeMessage::new_request(0x0123, b"ping".to_vec()),
+        &mut dst,
+      )
+      .unwrap();
+    assert_eq!(&dst[..], vectors::CLIENT_FRAME);
+
+    let mut src = BytesMut::from(vectors::DEVICE_FRAME);
+    let message = codec
+      .decode(&mut src)
+      .unwrap()
+      .unwrap()
+      .into_protobuf_message();
+    assert_eq!(message.protobuf_type, 0x0456);
+    assert_eq!(message.protobuf_data, b"pong");
+  }
+
+  #[test]
+  fn handshake_frame_layout() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let hello = codec.get_handshake_frame().unwrap().unwrap();
+    // Empty client hello, then the 32 byte ephemeral key and a 16 byte tag after a zero byte
+    assert_eq!(&hello[..7], &[0x01, 0x00, 0x00, 0x01, 0x00, 0x31, 0x00]);
+    assert_eq!(hello.len(), 7 + 48);
+  }
+
+  #[test]
+  fn parses_handshake_rejection() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    assert!(codec
+      .decode(&mut frame(b"\x01device\x00"))
+      .unwrap()
+      .is_none());
+
+    let mut rejection = frame(b"\x01Handshake MAC failure");
+    assert!(matches!(
+      codec.clone().decode(&mut rejection),
+      Err(Error::PskRejected)
+    ));
+    let mut rejection = frame(b"\x01Handshake error");
+    assert!(matches!(
+      codec.decode(&mut rejection),
+      Err(Error::HandshakeFailed(reason)) if reason == "Handshake error"
+    ));
+  }
+
+  #[test]
+  fn detects_plaintext_device() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let mut src = BytesMut::from(&[0x00, 0x05, 0x02][..]);
+    assert!(matches!(
+      codec.decode(&mut src),
+      Err(Error::EncryptionNotSupported)
+    ));
+  }
+
+  #[test]
+  fn only_a_plaintext_frame_means_no_encryption() {
+    let codec = Noise::new(&PSK.parse().unwrap(), None);
+    assert!(matches!(
+      codec.check_framing(Some(0x00)),
+      Err(Error::EncryptionNotSupported)
+    ));
+    assert!(matches!(
+      codec.check_framing(None),
+      Err(Error::HandshakeFailed(_))
+    ));
+    assert!(codec.check_framing(Some(0x01)).is_ok());
+  }
+
+  #[test]
+  fn encodes_16_bit_lengths_and_types() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let (mut decrypt, _) = handshake(&mut codec);
+
+    let mut dst = BytesMut::new();
+    codec
+      .encode(
+        EspHomeMessage::new_request(0x0123, vec![0xab; 300]),
+        &mut dst,
+      )
+      .unwrap();
+    // 4 byte data header, 300 bytes of data and the tag
+    assert_eq!(&dst[..3], &[0x01, 0x01, 0x40]);
+    assert_eq!(dst.len(), 3 + 320);
+
+    let plaintext = decrypt.decrypt_vec(&dst[3..]).unwrap();
+    assert_eq!(&plaintext[..4], &[0x01, 0x23, 0x01, 0x2c]);
+    assert_eq!(&plaintext[4..], &[0xab; 300][..]);
+  }
+
+  #[test]
+  fn decodes_16_bit_types_across_reads() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let (_, mut encrypt) = handshake(&mut codec);
+
+    let mut plaintext = vec![0x01, 0x23, 0x01, 0x2c];
+    plaintext.extend([0xcd; 300]);
+    let stream = frame(&encrypt.encrypt_vec(&plaintext));
+
+    // Nothing is decoded before the last read completed the frame
+    let mut src = BytesMut::new();
+    let mut decoded = None;
+    for chunk in stream.chunks(7) {
+      assert!(decoded.is_none());
+      src.extend_from_slice(chunk);
+      decoded = codec.decode(&mut src).unwrap();
+    }
+    let message = decoded.unwrap().into_protobuf_message();
+    assert_eq!(message.protobuf_type, 0x0123);
+    assert_eq!(message.protobuf_data, vec![0xcd; 300]);
+    assert!(src.is_empty());
+  }
+
+  #[test]
+  fn rejects_mismatching_data_length() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let (_, mut encrypt) = handshake(&mut codec);
+
+    let plaintext = [0x00, 0x07, 0x00, 0x05, 0x01];
+    let mut src = frame(&encrypt.encrypt_vec(&plaintext));
+    assert!(codec.decode(&mut src).is_err());
+  }
+
+  #[test]
+  fn rejects_oversize_messages() {
+    let mut codec = Noise::new(&PSK.parse().unwrap(), None);
+    let (mut decrypt, _) = handshake(&mut codec);
+
+    let largest = MAX_FRAME_LEN - DATA_HEADER_LEN - TAG_LEN;
+    let mut dst = BytesMut::new();
+    let oversize = EspHomeMessage::new_request(1, vec![0; largest + 1]);
+    assert!(codec.encode(oversize, &mut dst).is_err());
+    let wrong_type = EspHomeMessage::new_request(0x10000, Vec::new());
+    assert!(codec.encode(wrong_type, &mut dst).is_err());
+    assert!(dst.is_empty());
+
+    // Rejected messages leave the cipher usable for the next one
+    let fits = EspHomeMessage::new_request(1, vec![0; largest]);
+    codec.encode(fits, &mut dst).unwrap();
+    assert_eq!(&dst[..3], &[0x01, 0xff, 0xff]);
+    assert_eq!(decrypt.decrypt_vec(&dst[3..]).unwrap().len(), largest + 4);
+  }
+}
@@ -1,9 +1,14 @@
-use bytes::{BufMut, Bytes};
+use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
-use varuint::*;
 
 use super::{EspHomeMessage, FrameCodec};
+use crate::Error;
 
+/// Longest varint encoding of a `u32`
+const MAX_VARINT_LEN: usize = 5;
+
+/// Plaintext frames: a zero byte, the payload length and the message type as varints,
+/// then the payload.
 #[derive(Clone)]
 pub struct Plain {}
 
@@ -11,71 +16,207 @@ impl Plain {
   pub fn new() -> Self {
     Plain {}
   }
-}
 
-impl FrameCodec for Plain {
-  fn parse_frame(&self, src: &mut bytes::BytesMut) -> Result<(u8, u8), std::io::Error> {
-    let preamble: u8 = ReadVarint::read_varint(&mut src.as_ref()).unwrap();
-    if preamble != 0x00 {
-      return Err(std::io::Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Invalid preamble",
-      ));
-    }
-    let length: u8 = ReadVarint::read_varint(&mut src.as_ref()).unwrap();
-    let msg_type: u8 = ReadVarint::read_varint(&mut src.as_ref()).unwrap();
-
-    if src.len() < length as usize {
-      return Err(std::io::Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Invalid message length",
-      ));
+  /// Reads the header at the start of `src` without consuming it.
+  ///
+  /// Returns the header length, the payload length and the message type, or `None`
+  /// while the header is incomplete.
+  fn parse_frame(src: &[u8]) -> Result<Option<(usize, usize, u32)>, Error> {
+    let Some(&preamble) = src.first() else {
+      return Ok(None);
+    };
+    match preamble {
+      0x00 => {}
+      // A noise frame, the device only talks encrypted
+      0x01 => return Err(Error::RequiresEncryption),
+      _ => return Err(Error::Protocol("Invalid preamble".to_string())),
     }
+    let Some((length, length_len)) = read_varint(&src[1..])? else {
+      return Ok(None);
+    };
+    let Some((msg_type, msg_type_len)) = read_varint(&src[1 + length_len..])? else {
+      return Ok(None);
+    };
 
-    Ok((length, msg_type))
+    Ok(Some((
+      1 + length_len + msg_type_len,
+      length as usize,
+      msg_type,
+    )))
+  }
+}
+
+/// Decodes a protobuf style varint from the start of `src`, along with its length in bytes.
+///
+/// Returns `None` if `src` ends before the varint does.
+fn read_varint(src: &[u8]) -> Result<Option<(u32, usize)>, Error> {
+  let mut value: u64 = 0;
+  for (i, byte) in src.iter().take(MAX_VARINT_LEN).enumerate() {
+    value |= u64::from(byte & 0x7f) << (7 * i);
+    if byte & 0x80 == 0 {
+      let value =
+        u32::try_from(value).map_err(|_| Error::Protocol("Varint overflows u32".to_string()))?;
+      return Ok(Some((value, i + 1)));
+    }
+  }
+  if src.len() >= MAX_VARINT_LEN {
+    return Err(Error::Protocol("Varint too long".to_string()));
   }
+  Ok(None)
+}
 
-  fn get_handshake_frame(&mut self) -> Option<Bytes> {
-    None
+fn write_varint(dst: &mut BytesMut, mut value: u32) {
+  while value >= 0x80 {
+    dst.put_u8(value as u8 | 0x80);
+    value >>= 7;
   }
+  dst.put_u8(value as u8);
+}
 
-  fn close(&mut self) {}
+impl FrameCodec for Plain {
+  fn check_framing(&self, first_byte: Option<u8>) -> Result<(), Error> {
+    match first_byte {
+      // A noise frame, the device only talks encrypted
+      Some(0x01) => Err(Error::RequiresEncryption),
+      _ => Ok(()),
+    }
+  }
 }
 
 impl Decoder for Plain {
   type Item = EspHomeMessage;
-  type Error = std::io::Error;
+  type Error = Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    let Some((header_len, length, msg_type)) = Self::parse_frame(src)? else {
+      return Ok(None);
+    };
 
-  fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-    if src.is_empty() {
+    // Wait until the whole payload arrived
+    let frame_len = header_len + length;
+    if src.len() < frame_len {
       return Ok(None);
     }
 
-    let (length, msg_type) = self.parse_frame(src)?;
-    let msg = src.split_to(length as usize);
+    src.advance(header_len);
+    let msg = src.split_to(length);
 
-    Ok(Some(EspHomeMessage::new_response(
-      msg_type as u32,
-      msg.to_vec(),
-    )))
+    Ok(Some(EspHomeMessage::new_response(msg_type, msg.to_vec())))
   }
 }
 
 impl Encoder<EspHomeMessage> for Plain {
-  type Error = std::io::Error;
+  type Error = Error;
 
-  fn encode(&mut self, item: EspHomeMessage, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+  fn encode(&mut self, item: EspHomeMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
     let message = item.get_protobuf_message();
+    let length = u32::try_from(message.protobuf_data.len())
+      .map_err(|_| Error::Protocol("Message too large".to_string()))?;
+
+    dst.reserve(1 + 2 * MAX_VARINT_LEN + message.protobuf_data.len());
     dst.put_u8(0);
-    dst
-      .writer()
-      .write_varint(message.protobuf_data.len() as u64)
-      .unwrap();
-    dst
-      .writer()
-      .write_varint(message.protobuf_type as u64)
-      .unwrap();
+    write_varint(dst, length);
+    write_varint(dst, message.protobuf_type);
     dst.extend_from_slice(&message.protobuf_data);
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use proptest::prelude::*;
+
+  use super::*;
+
+  fn encode(protobuf_type: u32, protobuf_data: &[u8]) -> BytesMut {
+    let mut dst = BytesMut::new();
+    Plain::new()
+      .encode(
+        EspHomeMessage::new_request(protobuf_type, protobuf_data.to_vec()),
+        &mut dst,
+      )
+      .unwrap();
+    dst
+  }
+
+  #[test]
+  fn encodes_protobuf_varints() {
+    let frame = encode(300, &[0xab; 200]);
+    assert_eq!(&frame[..5], &[0x00, 0xc8, 0x01, 0xac, 0x02]);
+    assert_eq!(frame.len(), 5 + 200);
+  }
+
+  #[test]
+  fn rejects_invalid_preamble() {
+    let mut src = BytesMut::from(&[0x02, 0x00, 0x07][..]);
+    assert!(matches!(
+      Plain::new().decode(&mut src),
+      Err(Error::Protocol(_))
+    ));
+  }
+
+  #[test]
+  fn detects_encrypted_device() {
+    let mut src = BytesMut::from(&[0x01, 0x00, 0x00][..]);
+    assert!(matches!(
+      Plain::new().decode(&mut src),
+      Err(Error::RequiresEncryption)
+    ));
+  }
+
+  #[test]
+  fn rejects_overlong_varint() {
+    let mut src = BytesMut::from(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..]);
+    assert!(Plain::new().decode(&mut src).is_err());
+  }
+
+  proptest! {
+    #[test]
+    fn varint_round_trip(value: u32) {
+      let mut dst = BytesMut::new();
+      write_varint(&mut dst, value);
+      prop_assert_eq!(read_varint(&dst).unwrap(), Some((value, dst.len())));
+      prop_assert_eq!(read_varint(&dst[..dst.len() - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn frame_round_trip_across_reads(
+      messages in prop::collection::vec(
+        (any::<u32>(), prop::collection::vec(any::<u8>(), 0..300)),
+        1..8,
+      ),
+      chunk_len in 1usize..64,
+    ) {
+      let mut stream = BytesMut::new();
+      for (protobuf_type, protobuf_data) in &messages {
+        stream.extend_from_slice(&encode(*protobuf_type, protobuf_data));
+      }
+
+      // Feed the stream in small reads, as it may arrive over TCP
+      let mut codec = Plain::new();
+      let mut src = BytesMut::new();
+      let mut decoded = Vec::new();
+      for chunk in stream.chunks(chunk_len) {
+        src.extend_from_slice(chunk);
+        while let Some(message) = codec.decode(&mut src).unwrap() {
+          let message = message.into_protobuf_message();
+          decoded.push((message.protobuf_type, message.protobuf_data));
+        }
+      }
+
+      prop_assert_eq!(decoded, messages);
+      prop_assert!(src.is_empty());
+    }
+
+    #[test]
+    fn large_payload_round_trip(protobuf_type: u32, len in 0usize..200_000, byte: u8) {
+      let protobuf_data = vec![byte; len];
+      let mut src = encode(protobuf_type, &protobuf_data);
+
+      let message = Plain::new().decode(&mut src).unwrap().unwrap().into_protobuf_message();
+      prop_assert_eq!(message.protobuf_type, protobuf_type);
+      prop_assert_eq!(message.protobuf_data, protobuf_data);
+      prop_assert!(src.is_empty());
+    }
+  }
+}
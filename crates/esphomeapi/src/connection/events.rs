@@ -0,0 +1,67 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+  /// The connection is initialized, but connect() wasn't called yet
+  Initialized,
+  /// The socket has been opened, but the handshake and login haven't been completed
+  SocketOpened,
+  /// The handshake has been completed, messages can be exchanged
+  HandshakeCompleted,
+  /// The connection has been established, authenticated data can be exchanged
+  Connected,
+  Closed,
+}
+
+/// Why an established connection went away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+  /// The device asked to close the connection with a `DisconnectRequest`
+  RemoteRequest,
+  /// The device closed the socket
+  SocketClosed,
+  /// Reading from or writing to the socket failed
+  SocketError(String),
+  /// The device stopped answering pings
+  KeepAliveTimeout,
+  /// A frame couldn't be encoded or decoded
+  CodecError(String),
+  /// `disconnect()` was called or the client was dropped
+  ClientRequest,
+}
+
+impl fmt::Display for DisconnectReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::RemoteRequest => write!(f, "the device asked to disconnect"),
+      Self::SocketClosed => write!(f, "the device closed the socket"),
+      Self::SocketError(reason) => write!(f, "socket error: {}", reason),
+      Self::KeepAliveTimeout => write!(f, "the device stopped answering pings"),
+      Self::CodecError(reason) => write!(f, "codec error: {}", reason),
+      Self::ClientRequest => write!(f, "the client closed the connection"),
+    }
+  }
+}
+
+impl From<&crate::Error> for DisconnectReason {
+  fn from(error: &crate::Error) -> Self {
+    match error {
+      crate::Error::Io(e) => DisconnectReason::SocketError(e.to_string()),
+      _ => DisconnectReason::CodecError(error.to_string()),
+    }
+  }
+}
+
+/// Lifecycle changes of a connection, published to every subscriber.
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+  /// Handshake and hello/login completed, requests can be sent
+  Connected,
+  /// An established connection went away
+  Disconnected(DisconnectReason),
+  /// The socket was opened, but the noise handshake couldn't be completed, holds why,
+  /// e.g. [`Error::PskRejected`](crate::Error::PskRejected)
+  HandshakeFailed(crate::Error),
+  /// The device rejected the password sent in `ConnectRequest`
+  AuthFailed,
+}
@@ -0,0 +1,160 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, RwLock, Weak},
+};
+
+use super::Callback;
+
+/// Identifies a handler registered with `add_message_handler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+struct Handler {
+  id: HandlerId,
+  remove_after_call: bool,
+  callback: Arc<Callback>,
+}
+
+/// Callbacks registered per message type.
+#[derive(Default)]
+pub struct MessageHandlers {
+  next_id: u64,
+  handlers: HashMap<u32, Vec<Handler>>,
+}
+
+impl MessageHandlers {
+  pub fn add(
+    &mut self,
+    protobuf_type: u32,
+    callback: Callback,
+    remove_after_call: bool,
+  ) -> HandlerId {
+    let id = HandlerId(self.next_id);
+    self.next_id += 1;
+    self
+      .handlers
+      .entry(protobuf_type)
+      .or_default()
+      .push(Handler {
+        id,
+        remove_after_call,
+        callback: Arc::new(callback),
+      });
+    id
+  }
+
+  /// Removes the handler, returns `false` if it was already removed.
+  pub fn remove(&mut self, id: HandlerId) -> bool {
+    for handlers in self.handlers.values_mut() {
+      if let Some(index) = handlers.iter().position(|handler| handler.id == id) {
+        handlers.remove(index);
+        return true;
+      }
+    }
+    false
+  }
+
+  /// Returns the handlers of the message type, so they can be called without holding the lock.
+  pub fn get(&self, protobuf_type: u32) -> Vec<(HandlerId, bool, Arc<Callback>)> {
+    self
+      .handlers
+      .get(&protobuf_type)
+      .map(|handlers| {
+        handlers
+          .iter()
+          .map(|handler| {
+            (
+              handler.id,
+              handler.remove_after_call,
+              handler.callback.clone(),
+            )
+          })
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+}
+
+/// Removes its handler when dropped, unless it is detached.
+#[must_use = "dropping the guard removes the handler, call `detach()` to keep it"]
+pub struct HandlerGuard {
+  id: HandlerId,
+  handlers: Weak<RwLock<MessageHandlers>>,
+}
+
+impl HandlerGuard {
+  pub(crate) fn new(id: HandlerId, handlers: &Arc<RwLock<MessageHandlers>>) -> Self {
+    HandlerGuard {
+      id,
+      handlers: Arc::downgrade(handlers),
+    }
+  }
+
+  pub fn id(&self) -> HandlerId {
+    self.id
+  }
+
+  /// Keeps the handler registered for the lifetime of the connection,
+  /// it can still be removed with `remove_message_handler`.
+  pub fn detach(mut self) -> HandlerId {
+    self.handlers = Weak::new();
+    self.id
+  }
+}
+
+impl Drop for HandlerGuard {
+  fn drop(&mut self) {
+    if let Some(handlers) = self.handlers.upgrade() {
+      handlers.write().unwrap().remove(self.id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn handlers_with(remove_after_call: bool) -> (Arc<RwLock<MessageHandlers>>, HandlerId) {
+    let handlers = Arc::new(RwLock::new(MessageHandlers::default()));
+    let id = handlers
+      .write()
+      .unwrap()
+      .add(1, Box::new(|_, _| Ok(())), remove_after_call);
+    (handlers, id)
+  }
+
+  #[test]
+  fn dropping_the_guard_removes_the_handler() {
+    let (handlers, id) = handlers_with(false);
+    drop(HandlerGuard::new(id, &handlers));
+
+    assert!(handlers.read().unwrap().get(1).is_empty());
+    assert!(!handlers.write().unwrap().remove(id));
+  }
+
+  #[test]
+  fn detached_handler_stays_registered() {
+    let (handlers, id) = handlers_with(false);
+    assert_eq!(HandlerGuard::new(id, &handlers).detach(), id);
+
+    let registered = handlers.read().unwrap().get(1);
+    assert_eq!(registered.len(), 1);
+    assert_eq!(registered[0].0, id);
+    assert!(handlers.write().unwrap().remove(id));
+  }
+
+  #[test]
+  fn one_shot_handler_can_only_be_claimed_once() {
+    let (handlers, id) = handlers_with(true);
+    let guard = HandlerGuard::new(id, &handlers);
+
+    // Dispatch claims a one-shot handler by removing it before the call
+    let registered = handlers.read().unwrap().get(1);
+    assert!(registered[0].1);
+    assert!(handlers.write().unwrap().remove(id));
+    assert!(!handlers.write().unwrap().remove(id));
+    // The guard of a handler that already ran has nothing left to remove
+    drop(guard);
+    assert!(handlers.read().unwrap().get(1).is_empty());
+  }
+}
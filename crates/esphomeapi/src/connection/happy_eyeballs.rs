@@ -0,0 +1,130 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt as _};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Time an attempt gets before the next address is tried alongside it, see RFC 8305 section 5
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Races connection attempts to `addresses` and returns the first socket that connected.
+///
+/// The addresses are tried alternating between IPv6 and IPv4, a new attempt starts whenever
+/// the previous one failed or didn't finish within the attempt delay. The attempts still
+/// running are dropped once one of them succeeded.
+pub async fn connect(addresses: Vec<SocketAddr>) -> io::Result<(TcpStream, SocketAddr)> {
+  let mut remaining = interleave(addresses).into_iter();
+  let mut attempts = FuturesUnordered::new();
+  let mut last_error = None;
+
+  loop {
+    if let Some(address) = remaining.next() {
+      debug!(%address, "connecting");
+      attempts.push(async move { (address, TcpStream::connect(address).await) });
+    } else if attempts.is_empty() {
+      return Err(
+        last_error
+          .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")),
+      );
+    }
+
+    tokio::select! {
+      Some((address, result)) = attempts.next() => match result {
+        Ok(stream) => return Ok((stream, address)),
+        // The next attempt starts right away
+        Err(e) => {
+          debug!(%address, error = %e, "connection attempt failed");
+          last_error = Some(e);
+        }
+      },
+      _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if !remaining.as_slice().is_empty() => {}
+      else => {}
+    }
+  }
+}
+
+/// Orders the addresses alternating between the families, starting with IPv6.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let (v6, v4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+  let mut v6 = v6.into_iter();
+  let mut v4 = v4.into_iter();
+  let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+  loop {
+    match (v6.next(), v4.next()) {
+      (None, None) => return ordered,
+      (first, second) => ordered.extend(first.into_iter().chain(second)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+    addresses
+      .iter()
+      .map(|address| address.parse().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn interleave_alternates_starting_with_ipv6() {
+    let ordered = interleave(addresses(&[
+      "10.0.0.1:6053",
+      "10.0.0.2:6053",
+      "10.0.0.3:6053",
+      "[fe80::1]:6053",
+      "[fe80::2]:6053",
+    ]));
+    assert_eq!(
+      ordered,
+      addresses(&[
+        "[fe80::1]:6053",
+        "10.0.0.1:6053",
+        "[fe80::2]:6053",
+        "10.0.0.2:6053",
+        "10.0.0.3:6053",
+      ])
+    );
+  }
+
+  #[test]
+  fn interleave_keeps_a_single_family_in_order() {
+    let v4 = addresses(&["10.0.0.2:6053", "10.0.0.1:6053"]);
+    assert_eq!(interleave(v4.clone()), v4);
+    let v6 = addresses(&["[fe80::2]:6053", "[fe80::1]:6053"]);
+    assert_eq!(interleave(v6.clone()), v6);
+  }
+
+  #[tokio::test]
+  async fn moves_on_after_a_failed_attempt() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening = listener.local_addr().unwrap();
+    // Nothing listens on the port of a listener that was dropped
+    let closed = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+
+    let (_stream, address) = connect(vec![closed, listening]).await.unwrap();
+    assert_eq!(address, listening);
+  }
+
+  #[tokio::test]
+  async fn reports_the_last_error_when_every_attempt_failed() {
+    let closed = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let e = connect(vec![closed]).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+
+    let e = connect(Vec::new()).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+  }
+}
@@ -0,0 +1,161 @@
+use std::{
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::BoxedTransport;
+
+/// Snapshot of the counters of a connection, accumulated over all of its sessions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+  /// Messages waiting in the outbound queue
+  pub queued: u64,
+  /// Messages written to the socket
+  pub sent: u64,
+  /// Messages read from the socket
+  pub received: u64,
+  /// Messages lost, either queued when the session closed or skipped by a lagging subscriber
+  pub dropped: u64,
+  /// Bytes read from the socket, including framing and the handshake
+  pub bytes_in: u64,
+  /// Bytes written to the socket, including framing and the handshake
+  pub bytes_out: u64,
+  /// Round trip time of the last answered keep-alive ping
+  pub last_rtt: Option<Duration>,
+}
+
+/// Counters shared by the tasks of a connection.
+#[derive(Default)]
+pub struct Metrics {
+  queued: AtomicU64,
+  sent: AtomicU64,
+  received: AtomicU64,
+  dropped: AtomicU64,
+  bytes_in: AtomicU64,
+  bytes_out: AtomicU64,
+  ping_sent: Mutex<Option<Instant>>,
+  last_rtt: Mutex<Option<Duration>>,
+}
+
+impl Metrics {
+  pub fn snapshot(&self) -> ConnectionMetrics {
+    ConnectionMetrics {
+      queued: self.queued.load(Ordering::Relaxed),
+      sent: self.sent.load(Ordering::Relaxed),
+      received: self.received.load(Ordering::Relaxed),
+      dropped: self.dropped.load(Ordering::Relaxed),
+      bytes_in: self.bytes_in.load(Ordering::Relaxed),
+      bytes_out: self.bytes_out.load(Ordering::Relaxed),
+      last_rtt: *self.last_rtt.lock().unwrap(),
+    }
+  }
+
+  pub fn queued(&self, count: usize) {
+    self.queued.fetch_add(count as u64, Ordering::Relaxed);
+  }
+
+  /// Takes back messages that never made it into the queue.
+  pub fn unqueued(&self, count: usize) {
+    self.dequeue(count as u64);
+  }
+
+  pub fn sent(&self) {
+    self.dequeue(1);
+    self.sent.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // Saturating, a writer that is being aborted may still report after the queue was discarded
+  fn dequeue(&self, count: u64) {
+    let _ = self
+      .queued
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+        Some(queued.saturating_sub(count))
+      });
+  }
+
+  pub fn received(&self) {
+    self.received.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn dropped(&self, count: u64) {
+    self.dropped.fetch_add(count, Ordering::Relaxed);
+  }
+
+  /// Counts the messages left in the queue of a closed session as dropped.
+  pub fn discard_queued(&self) {
+    let queued = self.queued.swap(0, Ordering::Relaxed);
+    self.dropped(queued);
+  }
+
+  pub fn ping_sent(&self) {
+    self.ping_sent.lock().unwrap().replace(Instant::now());
+  }
+
+  pub fn pong_received(&self) {
+    if let Some(sent) = self.ping_sent.lock().unwrap().take() {
+      self.last_rtt.lock().unwrap().replace(sent.elapsed());
+    }
+  }
+}
+
+/// Counts the bytes going through a transport.
+pub struct Metered {
+  inner: BoxedTransport,
+  metrics: Arc<Metrics>,
+}
+
+impl Metered {
+  pub fn new(inner: BoxedTransport, metrics: Arc<Metrics>) -> Self {
+    Metered { inner, metrics }
+  }
+}
+
+impl AsyncRead for Metered {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    let result = self.inner.as_mut().poll_read(cx, buf);
+    let read = buf.filled().len() - filled;
+    self
+      .metrics
+      .bytes_in
+      .fetch_add(read as u64, Ordering::Relaxed);
+    result
+  }
+}
+
+impl AsyncWrite for Metered {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    let result = self.inner.as_mut().poll_write(cx, buf);
+    if let Poll::Ready(Ok(written)) = result {
+      self
+        .metrics
+        .bytes_out
+        .fetch_add(written as u64, Ordering::Relaxed);
+    }
+    result
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    self.inner.as_mut().poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    self.inner.as_mut().poll_shutdown(cx)
+  }
+}
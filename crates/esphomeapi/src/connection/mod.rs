@@ -1,413 +1,891 @@
 mod codec;
+mod events;
+mod handlers;
+mod happy_eyeballs;
+mod metrics;
+mod pending;
+mod reconnect;
+mod subscription;
 
 use std::{
-  collections::HashMap,
-  sync::{Arc, RwLock},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex, RwLock,
+  },
   time::{Duration, SystemTime},
 };
 
-use bytes::Bytes;
-use codec::{EspHomeCodec, EspHomeMessage, Noise, Plain};
-use futures::SinkExt as _;
-use protobuf::Message as _;
+use bytes::{Bytes, BytesMut};
+use codec::SharedCodec;
+use futures::{SinkExt as _, Stream};
+use protobuf::{reflect::MessageDescriptor, Message as _, MessageFull};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
-  io::{AsyncWriteExt as _, BufReader, BufWriter},
-  net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
+  io::{
+    AsyncBufReadExt as _, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader,
+    BufWriter, ReadHalf, WriteHalf,
   },
+  net::TcpStream,
+  sync::{broadcast, mpsc, mpsc::error::TrySendError, watch},
   task::JoinHandle,
   time::timeout,
 };
 use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::codec::{Decoder as _, Encoder as _, FramedRead, FramedWrite};
+use tracing::{debug, info, trace, warn, Instrument as _, Level, Span};
 
-use crate::{proto, Error, Result};
+use crate::{proto, utils::message_name, ClientOptions, Error, Result};
 
-use self::codec::FrameCodec;
+use self::{
+  handlers::MessageHandlers,
+  metrics::{Metered, Metrics},
+  pending::PendingRequests,
+  subscription::Subscriptions,
+};
 
 use crate::utils::Options as _;
-pub use codec::{Callback, ProtobufMessage};
-
-#[derive(Clone, Debug)]
-enum ConnectionState {
-  /// The connection is initialized, but connect() wasn't called yet
-  Initialized,
-  /// The socket has been opened, but the handshake and login haven't been completed
-  SocketOpened,
-  /// The handshake has been completed, messages can be exchanged
-  HandshakeCompleted,
-  /// The connection has been established, authenticated data can be exchanged
-  Connected,
-  Closed,
+pub use codec::EspHomeMessage;
+pub use codec::{
+  Callback, CodecFactory, EspHomeCodec, EspHomeMessageType, FrameCodec, ProtobufMessage, Psk,
+};
+pub use events::{ConnectionEvent, ConnectionState, DisconnectReason};
+pub use handlers::{HandlerGuard, HandlerId};
+pub use metrics::ConnectionMetrics;
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
+pub use subscription::{Subscription, TypedSubscription};
+
+/// API version sent in `HelloRequest`, devices with another major version are refused
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 10;
+/// Most queued batches written before the socket is flushed, bounds the latency of a busy writer
+const MAX_BATCHES_PER_FLUSH: usize = 32;
+
+/// A byte stream the protocol can run over
+trait Transport: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Transport for T {}
+
+type BoxedTransport = Pin<Box<dyn Transport>>;
+
+/// Sending side of a session's outbound queue
+#[derive(Clone)]
+struct Outbound {
+  tx: mpsc::Sender<Vec<EspHomeMessage>>,
+  metrics: Arc<Metrics>,
+}
+
+impl Outbound {
+  /// Queues the batch, waiting while the queue is full.
+  async fn send(&self, batch: Vec<EspHomeMessage>) -> Result<()> {
+    let count = batch.len();
+    self.metrics.queued(count);
+    if self.tx.send(batch).await.is_err() {
+      self.metrics.unqueued(count);
+      return Err(Error::NotConnected);
+    }
+    Ok(())
+  }
+
+  /// Queues the batch unless the queue is full, returns whether it was queued.
+  fn try_send(&self, batch: Vec<EspHomeMessage>) -> Result<bool> {
+    let count = batch.len();
+    self.metrics.queued(count);
+    match self.tx.try_send(batch) {
+      Ok(()) => Ok(true),
+      Err(e) => {
+        self.metrics.unqueued(count);
+        match e {
+          TrySendError::Full(_) => Ok(false),
+          TrySendError::Closed(_) => Err(Error::NotConnected),
+        }
+      }
+    }
+  }
+}
+
+/// The background tasks belonging to a single socket, from open until the peer goes away
+struct Session {
+  codec: SharedCodec,
+  tasks: Vec<JoinHandle<()>>,
+  closed: mpsc::UnboundedReceiver<DisconnectReason>,
+}
+
+impl Drop for Session {
+  fn drop(&mut self) {
+    self.codec.close();
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
 }
 
 #[derive(Clone)]
 pub struct Connection {
-  host: String,
-  port: u32,
-  password: Option<String>,
-  codec: EspHomeCodec,
-  state: ConnectionState,
-  is_connected: bool,
-  keep_alive_duration: Duration,
-  expected_name: Option<String>,
-  client_info: String,
-  message_handlers: Arc<RwLock<HashMap<u32, Vec<(bool, Callback)>>>>,
-  channel_tx: Option<tokio::sync::mpsc::Sender<EspHomeMessage>>,
+  options: ClientOptions,
+  state: Arc<watch::Sender<ConnectionState>>,
+  events: broadcast::Sender<ConnectionEvent>,
+  remote_disconnect: Arc<AtomicBool>,
+  closing: Arc<AtomicBool>,
+  supervisor: Arc<Mutex<Option<JoinHandle<()>>>>,
+  outstanding_pings: Arc<AtomicU32>,
+  hello: Arc<RwLock<Option<proto::api::HelloResponse>>>,
+  peer_addr: Arc<RwLock<Option<SocketAddr>>>,
+  span: Span,
+  reconnect_events: broadcast::Sender<ReconnectEvent>,
+  message_handlers: Arc<RwLock<MessageHandlers>>,
+  pending: Arc<Mutex<PendingRequests>>,
+  subscriptions: Arc<Mutex<Subscriptions>>,
+  channel_tx: Arc<RwLock<Option<Outbound>>>,
+  metrics: Arc<Metrics>,
 }
 
 impl Connection {
-  pub fn new(
-    host: String,
-    port: u32,
-    password: Option<String>,
-    expected_name: Option<String>,
-    psk: Option<String>,
-    client_info: Option<String>,
-    keep_alive_duration: Option<u32>,
-  ) -> Self {
-    let codec = match psk {
-      Some(psk) => EspHomeCodec::Noise(Arc::new(RwLock::new(Noise::new(
-        psk,
-        expected_name.clone(),
-      )))),
-      None => EspHomeCodec::Plain(Arc::new(RwLock::new(Plain::new()))),
-    };
+  pub fn new(options: ClientOptions) -> Self {
+    let (events, _) = broadcast::channel(16);
+    let (reconnect_events, _) = broadcast::channel(16);
 
-    // Initialize message handlers
-    let mut message_handlers: HashMap<u32, Vec<(bool, Callback)>> = HashMap::new();
-    proto::api::file_descriptor()
-      .messages()
-      .for_each(|msg_descriptor| {
-        if let Some(message_options) = msg_descriptor.proto().options.as_ref() {
-          match proto::api_options::exts::id.get(message_options) {
-            Some(message_type) => {
-              message_handlers.insert(message_type, Vec::new());
-            }
-            None => {}
-          }
-        }
-      });
+    let span = tracing::info_span!("esphome", host = %options.address, port = options.port);
+    let metrics = Arc::new(Metrics::default());
+    let subscriptions = Subscriptions::new(options.subscription_capacity, metrics.clone());
+    let connection = Connection {
+      options,
+      state: Arc::new(watch::channel(ConnectionState::Initialized).0),
+      events,
+      remote_disconnect: Arc::new(AtomicBool::new(false)),
+      closing: Arc::new(AtomicBool::new(false)),
+      supervisor: Arc::new(Mutex::new(None)),
+      outstanding_pings: Arc::new(AtomicU32::new(0)),
+      hello: Arc::new(RwLock::new(None)),
+      peer_addr: Arc::new(RwLock::new(None)),
+      span,
+      reconnect_events,
+      message_handlers: Arc::new(RwLock::new(MessageHandlers::default())),
+      pending: Arc::new(Mutex::new(PendingRequests::default())),
+      subscriptions: Arc::new(Mutex::new(subscriptions)),
+      channel_tx: Arc::new(RwLock::new(None)),
+      metrics,
+    };
 
-    Connection {
-      host,
-      port,
-      password,
-      codec,
-      state: ConnectionState::Initialized,
-      is_connected: false,
-      keep_alive_duration: Duration::from_secs(keep_alive_duration.unwrap_or(20) as u64),
-      expected_name,
-      client_info: client_info.unwrap_or("esphome-rs".to_string()),
-      message_handlers: Arc::new(RwLock::new(message_handlers)),
-      channel_tx: None,
+    // The internal handlers are registered once, so they survive reconnects
+    let internal_handlers: [(u32, Callback); 4] = [
+      (
+        proto::api::DisconnectRequest::get_option_id(),
+        Box::new(Self::handle_disconnect_request),
+      ),
+      (
+        proto::api::PingRequest::get_option_id(),
+        Box::new(Self::handle_ping_request),
+      ),
+      (
+        proto::api::GetTimeRequest::get_option_id(),
+        Box::new(Self::handle_get_time_request),
+      ),
+      (
+        proto::api::PingResponse::get_option_id(),
+        Box::new(Self::handle_ping_response),
+      ),
+    ];
+    for (protobuf_type, handler) in internal_handlers {
+      connection
+        .add_message_handler(protobuf_type, handler, false)
+        .detach();
     }
+
+    connection
   }
 
-  pub async fn connect(&mut self, login: bool) -> Result<()> {
-    let stream = TcpStream::connect(format!("{}:{}", self.host, self.port)).await?;
-    let (reader, mut writer) = stream.into_split();
-    self.state = ConnectionState::SocketOpened;
-    println!("Connected to {}:{}", self.host, self.port);
+  /// Returns a receiver that is notified about every reconnect attempt.
+  pub fn reconnect_events(&self) -> broadcast::Receiver<ReconnectEvent> {
+    self.reconnect_events.subscribe()
+  }
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-    self.channel_tx = Some(tx.clone());
+  /// Returns a receiver that is notified about every lifecycle change of the connection.
+  pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+    self.events.subscribe()
+  }
 
-    let handshake_frame = self.codec.get_handshake_frame();
+  /// Returns a receiver that always holds the current state of the connection.
+  pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+    self.state.subscribe()
+  }
 
-    let mut reader = FramedRead::new(BufReader::new(reader), self.codec.clone());
+  pub fn state(&self) -> ConnectionState {
+    self.state.borrow().clone()
+  }
 
-    self
-      .init_handshake(handshake_frame, &mut reader, &mut writer)
+  pub fn is_connected(&self) -> bool {
+    *self.state.borrow() == ConnectionState::Connected
+  }
+
+  /// Time the device is given to answer a request unless a call overrides it.
+  pub fn request_timeout(&self) -> Duration {
+    self.options.request_timeout
+  }
+
+  /// Returns the `HelloResponse` of the last successful connect.
+  pub fn hello_response(&self) -> Option<proto::api::HelloResponse> {
+    self.hello.read().unwrap().clone()
+  }
+
+  /// Returns the counters of the connection, accumulated since it was created.
+  pub fn metrics(&self) -> ConnectionMetrics {
+    self.metrics.snapshot()
+  }
+
+  /// Returns the address the current socket was connected to, `None` for streams passed to
+  /// `connect_with_stream`.
+  pub fn peer_addr(&self) -> Option<SocketAddr> {
+    *self.peer_addr.read().unwrap()
+  }
+
+  fn set_state(&self, state: ConnectionState) {
+    self.state.send_replace(state);
+  }
+
+  fn emit(&self, event: ConnectionEvent) {
+    // Sending only fails when nobody is subscribed
+    let _ = self.events.send(event);
+  }
+
+  pub async fn connect(&mut self, login: bool) -> Result<()> {
+    self.closing.store(false, Ordering::SeqCst);
+    let span = self.span.clone();
+    let session = self.open_session(login).instrument(span).await?;
+    self.spawn_supervisor(session, login, true);
+    Ok(())
+  }
+
+  /// Runs the protocol over an already opened `stream` instead of a TCP connection to
+  /// the configured address, e.g. a Unix socket bridge, a tunnel or an in-memory duplex.
+  ///
+  /// The connection can't be re-established once the stream closes, the reconnect
+  /// policy is ignored.
+  pub async fn connect_with_stream<S>(&mut self, stream: S, login: bool) -> Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+  {
+    self.closing.store(false, Ordering::SeqCst);
+    self.peer_addr.write().unwrap().take();
+    let span = self.span.clone();
+    let session = self
+      .start_session(Box::pin(stream), self.make_codec(), login, false)
+      .instrument(span)
       .await?;
+    self.spawn_supervisor(session, login, false);
+    Ok(())
+  }
 
-    self.add_message_handler(
-      proto::api::DisconnectRequest::get_option_id(),
-      Box::new(Self::handle_disconnect_request),
-      false,
-    );
-    self.add_message_handler(
-      proto::api::PingRequest::get_option_id(),
-      Box::new(Self::handle_ping_request),
-      false,
-    );
-    self.add_message_handler(
-      proto::api::GetTimeRequest::get_option_id(),
-      Box::new(Self::handle_get_time_request),
-      false,
+  fn spawn_supervisor(&self, session: Session, login: bool, reconnect: bool) {
+    let mut connection = self.clone();
+    let supervisor = tokio::spawn(
+      async move {
+        connection.supervise(session, login, reconnect).await;
+      }
+      .instrument(self.span.clone()),
     );
+    if let Some(previous) = self.supervisor.lock().unwrap().replace(supervisor) {
+      previous.abort();
+    }
+  }
 
-    // Reading messages from TCP stream and sending them to the mpsc channel
-    tokio::spawn(async move {
-      let tx = tx.clone();
-      loop {
-        let frame = reader.next().await;
-        match frame {
-          Some(frame) => match frame {
-            Ok(frame) => {
-              tx.send(frame).await.unwrap();
-            }
-            Err(e) => {
-              println!("Error reading frame: {:?}", e);
-            }
-          },
-          None => {
-            println!("Connection closed");
-            break;
-          }
+  /// Closes the connection and stops all background tasks.
+  ///
+  /// Unless `force` is set, the device is told about it with a `DisconnectRequest`
+  /// first and its `DisconnectResponse` is awaited. Requests still waiting for a
+  /// response fail with [`Error::Disconnected`] holding [`DisconnectReason::ClientRequest`].
+  pub async fn disconnect(&self, force: bool) -> Result<()> {
+    // Keep the supervisor from reconnecting once the device closes the socket
+    self.closing.store(true, Ordering::SeqCst);
+
+    if !force && self.is_connected() {
+      if let Err(e) = self
+        .request::<_, proto::api::DisconnectResponse>(proto::api::DisconnectRequest::new())
+        .await
+      {
+        self
+          .span
+          .in_scope(|| warn!(error = %e, "no DisconnectResponse"));
+      }
+    }
+
+    self.shutdown();
+    Ok(())
+  }
+
+  /// Tears down the session and the supervisor without talking to the device.
+  pub(crate) fn shutdown(&self) {
+    self.closing.store(true, Ordering::SeqCst);
+    // Dropping the session owned by the supervisor aborts the session's tasks
+    if let Some(supervisor) = self.supervisor.lock().unwrap().take() {
+      supervisor.abort();
+    }
+    self.channel_tx.write().unwrap().take();
+    self.metrics.discard_queued();
+    self
+      .pending
+      .lock()
+      .unwrap()
+      .close(&DisconnectReason::ClientRequest);
+
+    let state = self.state();
+    if state != ConnectionState::Initialized && state != ConnectionState::Closed {
+      self.set_state(ConnectionState::Closed);
+      self.emit(ConnectionEvent::Disconnected(
+        DisconnectReason::ClientRequest,
+      ));
+    }
+  }
+
+  /// Waits for the session to drop and re-establishes it according to the reconnect policy.
+  async fn supervise(&mut self, mut session: Session, login: bool, reconnect: bool) {
+    loop {
+      let reason = session
+        .closed
+        .recv()
+        .await
+        .unwrap_or(DisconnectReason::SocketClosed);
+      if self.closing.load(Ordering::SeqCst) {
+        // disconnect() tears down the session and reports it
+        break;
+      }
+      drop(session);
+      self.channel_tx.write().unwrap().take();
+      self.metrics.discard_queued();
+      self.pending.lock().unwrap().close(&reason);
+      self.set_state(ConnectionState::Closed);
+      warn!(%reason, "connection lost");
+      self.emit(ConnectionEvent::Disconnected(reason));
+
+      if !reconnect {
+        break;
+      }
+      match self.reconnect(login).await {
+        Some(new_session) => session = new_session,
+        None => break,
+      }
+    }
+  }
+
+  async fn reconnect(&mut self, login: bool) -> Option<Session> {
+    let policy = self.options.reconnect_policy.clone()?;
+
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      if policy.max_attempts.is_some_and(|max| attempt > max) {
+        let _ = self.reconnect_events.send(ReconnectEvent::GaveUp {
+          attempts: attempt - 1,
+        });
+        return None;
+      }
+
+      let delay = policy.delay(attempt);
+      let _ = self
+        .reconnect_events
+        .send(ReconnectEvent::Scheduled { attempt, delay });
+      tokio::time::sleep(delay).await;
+
+      match self.open_session(login).await {
+        Ok(session) => {
+          let _ = self
+            .reconnect_events
+            .send(ReconnectEvent::Connected { attempt });
+          return Some(session);
+        }
+        Err(e) => {
+          warn!(attempt, error = %e, "reconnect failed");
+          let _ = self
+            .reconnect_events
+            .send(ReconnectEvent::Failed { attempt, error: e });
         }
       }
-      println!("Reader task finished");
-    });
+    }
+  }
 
-    let tx = self.channel_tx.clone().unwrap();
-    let message_handlers = self.message_handlers.clone();
-    let connection = Arc::new(RwLock::new(self.clone()));
-    // Create a new codec for the writer
-    let mut writer = FramedWrite::new(BufWriter::new(writer), self.codec.clone());
-
-    // Spawn a new task to handle messages from the mpsc channel
-    // This has to be spawned before any writing to the mpsc channel
-    tokio::spawn(async move {
-      while let Some(message) = rx.recv().await {
-        match message.message_type {
-          codec::EspHomeMessageType::Response { protobuf_message } => {
-            println!("Received Response: {:?}", protobuf_message);
-            if let Some(handlers) = message_handlers
-              .write()
-              .unwrap()
-              .get_mut(&protobuf_message.protobuf_type)
-            {
-              handlers.retain(|(remove_after_call, callback)| {
-                // Call the handler function with the message
-                let _ = callback(
-                  connection.clone(),
-                  ProtobufMessage {
-                    protobuf_type: protobuf_message.protobuf_type,
-                    protobuf_data: protobuf_message.protobuf_data.clone(),
-                  },
-                );
-                !*remove_after_call
-              });
-            }
-          }
-          codec::EspHomeMessageType::Request { protobuf_message } => {
-            println!("Sending Request message {}", protobuf_message.protobuf_type);
-            writer
-              .send(EspHomeMessage::new_request(
-                protobuf_message.protobuf_type,
-                protobuf_message.protobuf_data,
-              ))
-              .await
-              .unwrap();
+  /// Connects to the device within the connect timeout and applies the socket options.
+  ///
+  /// All addresses of the device are raced against each other, the one that won is kept
+  /// as the peer address.
+  async fn open_socket(&self) -> Result<TcpStream> {
+    let (stream, address) = timeout(self.options.connect_timeout, async {
+      let addresses = self.resolve().await?;
+      Ok::<_, Error>(happy_eyeballs::connect(addresses).await?)
+    })
+    .await
+    .map_err(|_| Error::Timeout)??;
+    debug!(%address, "tcp connected");
+    self.peer_addr.write().unwrap().replace(address);
+
+    stream.set_nodelay(self.options.tcp_nodelay)?;
+    if let Some(tcp_keepalive) = self.options.tcp_keepalive {
+      SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(tcp_keepalive))?;
+    }
+    Ok(stream)
+  }
+
+  /// Returns the configured addresses, or the ones the host name resolves to.
+  async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+    let port = u16::try_from(self.options.port)
+      .map_err(|_| Error::InvalidRequest(format!("invalid port {}", self.options.port)))?;
+    if !self.options.addresses.is_empty() {
+      return Ok(
+        self
+          .options
+          .addresses
+          .iter()
+          .map(|address| SocketAddr::new(*address, port))
+          .collect(),
+      );
+    }
+    Ok(
+      tokio::net::lookup_host((self.options.address.as_str(), port))
+        .await?
+        .collect(),
+    )
+  }
+
+  fn make_codec(&self) -> SharedCodec {
+    let codec: Box<dyn FrameCodec> = match (&self.options.codec, &self.options.psk) {
+      (Some(factory), _) => factory(),
+      (None, Some(psk)) => Box::new(EspHomeCodec::noise(psk, self.options.expected_name.clone())),
+      (None, None) => Box::new(EspHomeCodec::plaintext()),
+    };
+    SharedCodec::new(codec)
+  }
+
+  /// Opens a TCP connection to the device and starts a session on it.
+  ///
+  /// With `plaintext_fallback` set, a device that turns out not to use encryption is
+  /// connected to again without it.
+  async fn open_session(&mut self, login: bool) -> Result<Session> {
+    let stream = self.open_socket().await?;
+    let codec = self.make_codec();
+    let plaintext_fallback = self.options.plaintext_fallback && self.options.codec.is_none();
+    match self
+      .start_session(Box::pin(stream), codec, login, plaintext_fallback)
+      .await
+    {
+      Err(Error::EncryptionNotSupported) if plaintext_fallback => {
+        warn!("device doesn't use encryption, falling back to plaintext");
+        let stream = match self.open_socket().await {
+          Ok(stream) => stream,
+          Err(e) => {
+            self.set_state(ConnectionState::Closed);
+            return Err(e);
           }
-          codec::EspHomeMessageType::RequestWithAwait {
-            protobuf_message,
-            response_protobuf_type,
-            tx: oneshot_tx,
-          } => {
-            println!(
-              "Sending RequestWithAwait message {}",
-              protobuf_message.protobuf_type
-            );
-            writer
-              .send(EspHomeMessage::new_request(
-                protobuf_message.protobuf_type,
-                protobuf_message.protobuf_data,
-              ))
-              .await
-              .unwrap();
-            while let Some(message) = rx.recv().await {
-              match message.message_type {
-                codec::EspHomeMessageType::Response { protobuf_message } => {
-                  if protobuf_message.protobuf_type == response_protobuf_type {
-                    println!(
-                      "Received Response for RequestWithAwait: {:?}",
-                      protobuf_message
-                    );
-
-                    if let Err(_) = oneshot_tx.send(protobuf_message.clone()) {
-                      println!("Error sending response to oneshot channel");
-                    }
-
-                    if let Some(handlers) = message_handlers
-                      .write()
-                      .unwrap()
-                      .get_mut(&protobuf_message.protobuf_type)
-                    {
-                      handlers.retain(|(remove_after_call, callback)| {
-                        // Call the handler function with the message
-
-                        let _ = callback(
-                          connection.clone(),
-                          ProtobufMessage {
-                            protobuf_type: protobuf_message.protobuf_type,
-                            protobuf_data: protobuf_message.protobuf_data.clone(),
-                          },
-                        );
-                        !*remove_after_call
-                      });
-                    }
-                    break;
-                  } else {
-                    println!(
-                      "Received ummatched Response for RequestWithAwait: {:?}",
-                      protobuf_message
-                    );
-                    tx.send(EspHomeMessage::new_response(
-                      protobuf_message.protobuf_type,
-                      protobuf_message.protobuf_data,
-                    ))
-                    .await
-                    .unwrap();
-                  }
-                }
-                _ => {}
+        };
+        let codec = SharedCodec::new(Box::new(EspHomeCodec::plaintext()));
+        self
+          .start_session(Box::pin(stream), codec, login, false)
+          .await
+      }
+      result => result,
+    }
+  }
+
+  /// Completes the handshake and hello/login over `stream`, and spawns the tasks serving it.
+  ///
+  /// With `plaintext_fallback` set, a device answering without encryption isn't reported
+  /// as a failed handshake, the caller starts another session without it.
+  async fn start_session(
+    &mut self,
+    stream: BoxedTransport,
+    mut codec: SharedCodec,
+    login: bool,
+    plaintext_fallback: bool,
+  ) -> Result<Session> {
+    let handshake_frame = codec.get_handshake_frame()?;
+    let handshake = handshake_frame.is_some();
+
+    let stream: BoxedTransport = Box::pin(Metered::new(stream, self.metrics.clone()));
+    let (reader, mut writer) = tokio::io::split(stream);
+    self.remote_disconnect.store(false, Ordering::SeqCst);
+    self.set_state(ConnectionState::SocketOpened);
+    debug!("socket opened");
+
+    let mut reader = FramedRead::new(BufReader::new(reader), codec.clone());
+
+    if let Err(e) = self
+      .init_handshake(handshake_frame, &mut reader, &mut writer)
+      .await
+    {
+      if !(plaintext_fallback && matches!(e, Error::EncryptionNotSupported)) {
+        self.set_state(ConnectionState::Closed);
+        self.emit(ConnectionEvent::HandshakeFailed(e.clone()));
+      }
+      return Err(e);
+    }
+
+    // The first message is exchanged before anything else runs, so a device speaking
+    // the other transport is reported as such rather than as a dropped session
+    let hello = match self
+      .exchange_hello(&mut codec, &mut reader, &mut writer, !handshake)
+      .await
+    {
+      Ok(hello) => hello,
+      Err(e) => {
+        self.set_state(ConnectionState::Closed);
+        return Err(e);
+      }
+    };
+
+    let (inbound_tx, inbound_rx) = mpsc::channel(self.options.inbound_capacity);
+    let (outbound_tx, outbound_rx) = mpsc::channel(self.options.outbound_capacity);
+    let outbound = Outbound {
+      tx: outbound_tx,
+      metrics: self.metrics.clone(),
+    };
+    let (close_tx, closed) = mpsc::unbounded_channel();
+    let mut session = Session {
+      codec: codec.clone(),
+      tasks: Vec::new(),
+      closed,
+    };
+
+    // Reading messages from the stream and sending them to the dispatcher
+    let reader_close_tx = close_tx.clone();
+    let remote_disconnect = self.remote_disconnect.clone();
+    let reader_metrics = self.metrics.clone();
+    session.tasks.push(tokio::spawn(
+      async move {
+        let reason = loop {
+          match reader.next().await {
+            Some(Ok(frame)) => {
+              reader_metrics.received();
+              if inbound_tx.send(frame).await.is_err() {
+                break DisconnectReason::SocketClosed;
               }
             }
-          }
-          codec::EspHomeMessageType::RequestWithAwaitMultipleUntil {
-            protobuf_message,
-            response_protobuf_types,
-            until_protobuf_type,
-            tx: mpsc_tx,
-          } => {
-            println!(
-              "Sending RequestWithAwaitMultipleUntil message {}",
-              protobuf_message.protobuf_type
-            );
-            writer
-              .send(EspHomeMessage::new_request(
-                protobuf_message.protobuf_type,
-                protobuf_message.protobuf_data,
-              ))
-              .await
-              .unwrap();
-            while let Some(message) = rx.recv().await {
-              println!("Received message: {:?}", message);
-              match message.message_type {
-                codec::EspHomeMessageType::Response { protobuf_message } => {
-                  if response_protobuf_types.contains(&protobuf_message.protobuf_type) {
-                    println!(
-                      "Received Response for RequestWithAwaitMultipleUntil: {:?}",
-                      protobuf_message
-                    );
-
-                    if let Err(_) = mpsc_tx.send(protobuf_message.clone()).await {
-                      println!("Error sending response to mpsc channel");
-                    }
-
-                    if let Some(handlers) = message_handlers
-                      .write()
-                      .unwrap()
-                      .get_mut(&protobuf_message.protobuf_type)
-                    {
-                      handlers.retain(|(remove_after_call, callback)| {
-                        // Call the handler function with the message
-                        let _ = callback(
-                          connection.clone(),
-                          ProtobufMessage {
-                            protobuf_type: protobuf_message.protobuf_type,
-                            protobuf_data: protobuf_message.protobuf_data.clone(),
-                          },
-                        );
-                        !*remove_after_call
-                      });
-                    }
-                  } else if protobuf_message.protobuf_type == until_protobuf_type {
-                    println!(
-                      "Received until Response for RequestWithAwaitMultipleUntil: {:?}",
-                      protobuf_message
-                    );
-                    break;
-                  } else {
-                    println!(
-                      "Received ummatched Response for RequestWithAwaitMultipleUntil: {:?}",
-                      protobuf_message
-                    );
-                    tx.send(EspHomeMessage::new_response(
-                      protobuf_message.protobuf_type,
-                      protobuf_message.protobuf_data,
-                    ))
-                    .await
-                    .unwrap();
-                  }
-                }
-                _ => {}
-              }
+            Some(Err(e)) => {
+              warn!(error = %e, "reading frame failed");
+              break DisconnectReason::from(&e);
+            }
+            None if remote_disconnect.load(Ordering::SeqCst) => {
+              break DisconnectReason::RemoteRequest;
             }
+            None => break DisconnectReason::SocketClosed,
           }
+        };
+        debug!(%reason, "socket closed");
+        let _ = reader_close_tx.send(reason);
+      }
+      .in_current_span(),
+    ));
+
+    // Handing received messages to the waiting requests and the message handlers
+    let message_handlers = self.message_handlers.clone();
+    let pending = self.pending.clone();
+    let subscriptions = self.subscriptions.clone();
+    let connection = Arc::new(RwLock::new(self.clone()));
+    session.tasks.push(tokio::spawn(
+      Self::dispatch(
+        inbound_rx,
+        message_handlers,
+        pending,
+        subscriptions,
+        connection,
+      )
+      .in_current_span(),
+    ));
+
+    // Writing requests to the stream, independently of the incoming traffic
+    let writer = FramedWrite::new(BufWriter::new(writer), codec);
+    let writer_close_tx = close_tx.clone();
+    let writer_metrics = self.metrics.clone();
+    session.tasks.push(tokio::spawn(
+      async move {
+        if let Err(e) = Self::write(outbound_rx, writer, &writer_metrics).await {
+          warn!(error = %e, "writing frame failed");
+          let _ = writer_close_tx.send(DisconnectReason::from(&e));
         }
       }
-    });
+      .in_current_span(),
+    ));
+
+    if let Err(e) = self.init_hello(hello, login, &outbound).await {
+      self.metrics.discard_queued();
+      self
+        .pending
+        .lock()
+        .unwrap()
+        .close(&DisconnectReason::ClientRequest);
+      self.set_state(ConnectionState::Closed);
+      return Err(e);
+    }
+    // Requests are only accepted once logged in, the device drops clients sending
+    // anything else before the ConnectResponse
+    *self.channel_tx.write().unwrap() = Some(outbound.clone());
+    self.set_state(ConnectionState::Connected);
+    self.emit(ConnectionEvent::Connected);
+    if !self.options.keep_alive.is_zero() {
+      session.tasks.push(self.keep_alive(outbound, close_tx));
+    }
+
+    Ok(session)
+  }
 
-    self.init_hello(login).await?;
-    self.state = ConnectionState::Connected;
-    self.keep_alive(self.keep_alive_duration);
+  async fn dispatch(
+    mut rx: mpsc::Receiver<EspHomeMessage>,
+    message_handlers: Arc<RwLock<MessageHandlers>>,
+    pending: Arc<Mutex<PendingRequests>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    connection: Arc<RwLock<Connection>>,
+  ) {
+    while let Some(message) = rx.recv().await {
+      let protobuf_message = message.into_protobuf_message();
+      log_message("in", &protobuf_message);
 
+      pending.lock().unwrap().dispatch(&protobuf_message);
+      subscriptions.lock().unwrap().dispatch(&protobuf_message);
+
+      // Handlers run without the lock held, so they can add or remove handlers themselves
+      let handlers = message_handlers
+        .read()
+        .unwrap()
+        .get(protobuf_message.protobuf_type);
+      for (id, remove_after_call, callback) in handlers {
+        // A one-shot handler runs only if it wasn't removed in the meantime
+        if remove_after_call && !message_handlers.write().unwrap().remove(id) {
+          continue;
+        }
+        // A failing handler only affects its own message
+        if let Err(e) = callback(connection.clone(), protobuf_message.clone()) {
+          warn!(
+            message = message_name(protobuf_message.protobuf_type),
+            error = %e,
+            "message handler failed"
+          );
+        }
+      }
+    }
+  }
+
+  /// Writes the queued batches, each one contiguously.
+  ///
+  /// Batches that are already waiting are encoded together and flushed once.
+  async fn write(
+    mut rx: mpsc::Receiver<Vec<EspHomeMessage>>,
+    mut writer: FramedWrite<BufWriter<WriteHalf<BoxedTransport>>, SharedCodec>,
+    metrics: &Metrics,
+  ) -> Result<()> {
+    while let Some(mut batch) = rx.recv().await {
+      let mut batches = 0;
+      loop {
+        for message in batch {
+          log_message("out", message.get_protobuf_message());
+          writer.feed(message).await?;
+          metrics.sent();
+        }
+        batches += 1;
+        if batches == MAX_BATCHES_PER_FLUSH {
+          break;
+        }
+        match rx.try_recv() {
+          Ok(next) => batch = next,
+          Err(_) => break,
+        }
+      }
+      writer.flush().await?;
+    }
     Ok(())
   }
 
+  /// Sends the handshake frame and feeds the device's answers to the codec until it
+  /// reports the handshake as completed.
   async fn init_handshake(
-    &mut self,
+    &self,
     handshake_frame: Option<Bytes>,
-    reader: &mut FramedRead<BufReader<OwnedReadHalf>, EspHomeCodec>,
-    writer: &mut OwnedWriteHalf,
+    reader: &mut FramedRead<BufReader<ReadHalf<BoxedTransport>>, SharedCodec>,
+    writer: &mut WriteHalf<BoxedTransport>,
   ) -> Result<()> {
     if let Some(handshake_frame) = handshake_frame {
-      // Communication is encrypted
       writer.write_all(&handshake_frame).await?;
-      let handshake_response = reader.next().await;
-      if handshake_response.is_none() {
-        return Err(Error::from(std::io::Error::new(
-          std::io::ErrorKind::ConnectionAborted,
-          "Handshake failed",
-        )));
+      timeout(self.options.handshake_timeout, async {
+        Self::check_framing(reader).await?;
+        Self::read_handshake(reader).await
+      })
+      .await
+      .map_err(|_| Error::Timeout)??;
+      self.set_state(ConnectionState::HandshakeCompleted);
+      debug!("handshake completed");
+    }
+
+    Ok(())
+  }
+
+  /// Decodes handshake frames out of the reader's buffer, reading more as needed.
+  ///
+  /// The device doesn't send anything else before the `HelloRequest`, so at most a partial
+  /// frame is left in the buffer for the reader to complete.
+  async fn read_handshake(
+    reader: &mut FramedRead<BufReader<ReadHalf<BoxedTransport>>, SharedCodec>,
+  ) -> Result<()> {
+    let mut codec = reader.decoder().clone();
+    let mut read = BytesMut::new();
+    loop {
+      if codec.decode(reader.read_buffer_mut())?.is_some() {
+        return Err(Error::HandshakeFailed(
+          "message received during handshake".to_string(),
+        ));
       }
-      let handshake_message_response = handshake_response.unwrap()?;
-      let handshake_protobuf = handshake_message_response.get_protobuf_message();
-      if handshake_protobuf.protobuf_type == 0
-        && handshake_protobuf.protobuf_data == "Handshake completed".as_bytes().to_vec()
-      {
-        self.state = ConnectionState::HandshakeCompleted;
-        println!("Handshake completed");
+      if codec.handshake_completed() {
+        return Ok(());
+      }
+      if reader.get_mut().read_buf(&mut read).await? == 0 {
+        return Err(Error::HandshakeFailed(
+          "connection closed during handshake".to_string(),
+        ));
       }
+      reader.read_buffer_mut().unsplit(read.split());
     }
-    self.is_connected = true;
+  }
 
-    Ok(())
+  /// Lets the codec check the first byte of the device's first answer, for the native API
+  /// its preamble tells whether the device expects encrypted (0x01) or plaintext (0x00) frames.
+  async fn check_framing(
+    reader: &mut FramedRead<BufReader<ReadHalf<BoxedTransport>>, SharedCodec>,
+  ) -> Result<()> {
+    let first_byte = reader.get_mut().fill_buf().await?.first().copied();
+    reader.decoder().check_framing(first_byte)
   }
 
-  async fn init_hello(&mut self, login: bool) -> Result<()> {
-    let hello = self.make_hello_request();
-    let expected_name = self.expected_name.clone();
+  /// Sends the `HelloRequest` and reads the answer directly from the stream.
+  async fn exchange_hello(
+    &self,
+    codec: &mut SharedCodec,
+    reader: &mut FramedRead<BufReader<ReadHalf<BoxedTransport>>, SharedCodec>,
+    writer: &mut WriteHalf<BoxedTransport>,
+    first_answer: bool,
+  ) -> Result<proto::api::HelloResponse> {
+    let request = Self::make_request(&self.make_hello_request())?;
+    log_message("out", request.get_protobuf_message());
+    let mut frame = BytesMut::new();
+    codec.encode(request, &mut frame)?;
+    writer.write_all(&frame).await?;
+    self.metrics.queued(1);
+    self.metrics.sent();
+    let response = timeout(self.options.login_timeout, async {
+      // Without a handshake the hello is the first frame the device answers
+      if first_answer {
+        Self::check_framing(reader).await?;
+      }
+      reader
+        .next()
+        .await
+        .ok_or(Error::Disconnected(DisconnectReason::SocketClosed))?
+    })
+    .await
+    .map_err(|_| Error::Timeout)??
+    .into_protobuf_message();
+    self.metrics.received();
+    log_message("in", &response);
+    if response.protobuf_type != proto::api::HelloResponse::get_option_id() {
+      return Err(Error::UnknownMessageType(response.protobuf_type));
+    }
+    Ok(proto::api::HelloResponse::parse_from_bytes(
+      &response.protobuf_data,
+    )?)
+  }
 
-    let response = self
-      .send_message_await_response(Box::new(hello), proto::api::HelloResponse::get_option_id())
-      .await?;
-    let response = proto::api::HelloResponse::parse_from_bytes(&response.protobuf_data).unwrap();
-    let received_name = response.name;
-    if let Some(expected_name) = expected_name.clone() {
-      if received_name != expected_name {
-        println!("Received name does not match expected name");
-      } else {
-        println!("Received name matches expected name");
+  /// Checks the device's `HelloResponse` and logs in through `outbound`.
+  async fn init_hello(
+    &self,
+    response: proto::api::HelloResponse,
+    login: bool,
+    outbound: &Outbound,
+  ) -> Result<()> {
+    if let Some(expected_name) = &self.options.expected_name {
+      if response.name != *expected_name {
+        return Err(Error::ServerNameMismatch {
+          expected: expected_name.clone(),
+          received: response.name,
+        });
       }
     }
+    if response.api_version_major != API_VERSION_MAJOR {
+      return Err(Error::UnsupportedApiVersion {
+        major: response.api_version_major,
+        minor: response.api_version_minor,
+      });
+    }
+    if response.api_version_minor > API_VERSION_MINOR {
+      warn!(
+        major = response.api_version_major,
+        minor = response.api_version_minor,
+        "device uses a newer API version, some features may not work"
+      );
+    }
+    info!(
+      peer_addr = ?self.peer_addr(),
+      name = %response.name,
+      server_info = %response.server_info,
+      major = response.api_version_major,
+      minor = response.api_version_minor,
+      "connected"
+    );
+    *self.hello.write().unwrap() = Some(response);
 
     if login {
       let connect = self.make_connect_request();
-      self
-        .send_message_await_response(
-          Box::new(connect),
-          proto::api::ConnectResponse::get_option_id(),
+      let response = self
+        .exchange(
+          outbound,
+          vec![Box::new(connect)],
+          vec![proto::api::ConnectResponse::get_option_id()],
+          self.options.login_timeout,
         )
-        .await?;
+        .await?
+        .pop()
+        .ok_or_else(|| Error::Protocol("Expected exactly one response".to_string()))?;
+      let response = proto::api::ConnectResponse::parse_from_bytes(&response.protobuf_data)?;
+      if response.invalid_password {
+        self.emit(ConnectionEvent::AuthFailed);
+        return Err(Error::InvalidPassword);
+      }
     }
     Ok(())
   }
 
+  /// Sends `request` without waiting for an answer, the message id is taken from its proto options.
+  pub async fn send<Req: MessageFull>(&self, request: Req) -> Result<()> {
+    self.send_message(Box::new(request)).await
+  }
+
+  /// Sends `request` and returns the parsed `Resp` the device answers with.
+  ///
+  /// ```no_run
+  /// # async fn example(connection: esphomeapi::Connection) -> esphomeapi::Result<()> {
+  /// use esphomeapi::api::{DeviceInfoRequest, DeviceInfoResponse};
+  ///
+  /// let device_info: DeviceInfoResponse = connection.request(DeviceInfoRequest::new()).await?;
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub async fn request<Req: MessageFull, Resp: MessageFull>(&self, request: Req) -> Result<Resp> {
+    self
+      .request_with_timeout(request, self.options.request_timeout)
+      .await
+  }
+
+  /// Like [`Connection::request`], waiting `timeout_duration` instead of the configured
+  /// request timeout.
+  pub async fn request_with_timeout<Req: MessageFull, Resp: MessageFull>(
+    &self,
+    request: Req,
+    timeout_duration: Duration,
+  ) -> Result<Resp> {
+    let response = self
+      .send_message_await_response_timeout(
+        Box::new(request),
+        Self::message_id(&Resp::descriptor())?,
+        timeout_duration,
+      )
+      .await?;
+    Ok(Resp::parse_from_bytes(&response.protobuf_data)?)
+  }
+
   pub async fn send_message(&self, message: Box<dyn protobuf::MessageDyn>) -> Result<()> {
     self.send_messages(vec![message]).await
   }
@@ -417,89 +895,133 @@ impl Connection {
     message: Box<dyn protobuf::MessageDyn>,
     response_protobuf_type: u32,
   ) -> Result<ProtobufMessage> {
-    let responses = self
-      .send_messages_await_response(vec![message], vec![response_protobuf_type])
+    self
+      .send_message_await_response_timeout(
+        message,
+        response_protobuf_type,
+        self.options.request_timeout,
+      )
+      .await
+  }
+
+  /// Like [`Connection::send_message_await_response`], waiting `timeout_duration`
+  /// instead of the configured request timeout.
+  pub async fn send_message_await_response_timeout(
+    &self,
+    message: Box<dyn protobuf::MessageDyn>,
+    response_protobuf_type: u32,
+    timeout_duration: Duration,
+  ) -> Result<ProtobufMessage> {
+    let mut responses = self
+      .send_messages_await_response_timeout(
+        vec![message],
+        vec![response_protobuf_type],
+        timeout_duration,
+      )
       .await?;
-    if responses.len() != 1 {
-      return Err("Expected exactly one response".into());
-    }
-    Ok(responses[0].clone())
+    responses
+      .pop()
+      .ok_or_else(|| Error::Protocol("Expected exactly one response".to_string()))
   }
 
+  /// Sends the messages as one batch, flushed at once and not interleaved with other
+  /// requests on the wire.
+  ///
+  /// Nothing is sent if one of the messages can't be encoded.
   pub async fn send_messages(&self, messages: Vec<Box<dyn protobuf::MessageDyn>>) -> Result<()> {
-    let channel_tx = self.channel_tx.clone().unwrap();
-
-    for message in messages {
-      let protobuf_type = message
-        .descriptor_dyn()
-        .proto()
-        .options
-        .as_ref()
-        .and_then(|options| proto::api_options::exts::id.get(options))
-        .unwrap();
-      let protobuf_data = message.write_to_bytes_dyn().unwrap();
-      let request_message = EspHomeMessage::new_request(protobuf_type, protobuf_data);
-
-      match channel_tx.send(request_message).await {
-        Ok(_) => {}
-        Err(e) => {
-          return Err(format!("Error sending message: {:?}", e.0).into());
-        }
-      }
-    }
-    Ok(())
+    let channel_tx = self.sender()?;
+
+    let batch = messages
+      .iter()
+      .map(|message| Self::make_request(message.as_ref()))
+      .collect::<Result<Vec<_>>>()?;
+    channel_tx.send(batch).await
   }
 
   pub async fn send_messages_await_response(
     &self,
     messages: Vec<Box<dyn protobuf::MessageDyn>>,
     response_protobuf_types: Vec<u32>,
+  ) -> Result<Vec<ProtobufMessage>> {
+    self
+      .send_messages_await_response_timeout(
+        messages,
+        response_protobuf_types,
+        self.options.request_timeout,
+      )
+      .await
+  }
+
+  /// Like [`Connection::send_messages_await_response`], waiting `timeout_duration`
+  /// for each response instead of the configured request timeout.
+  pub async fn send_messages_await_response_timeout(
+    &self,
+    messages: Vec<Box<dyn protobuf::MessageDyn>>,
+    response_protobuf_types: Vec<u32>,
+    timeout_duration: Duration,
   ) -> Result<Vec<ProtobufMessage>> {
     if response_protobuf_types.len() != messages.len() {
-      return Err("Number of response types must match number of messages".into());
+      return Err(Error::InvalidRequest(
+        "Number of response types must match number of messages".to_string(),
+      ));
     }
 
-    let channel_tx = self.channel_tx.clone().unwrap();
+    let channel_tx = self.sender()?;
+    self
+      .exchange(
+        &channel_tx,
+        messages,
+        response_protobuf_types,
+        timeout_duration,
+      )
+      .await
+  }
 
-    let mut responses = Vec::new();
+  /// Sends the messages as one batch through `outbound` and waits for one response of
+  /// each type, regardless of the connection state.
+  async fn exchange(
+    &self,
+    outbound: &Outbound,
+    messages: Vec<Box<dyn protobuf::MessageDyn>>,
+    response_protobuf_types: Vec<u32>,
+    timeout_duration: Duration,
+  ) -> Result<Vec<ProtobufMessage>> {
+    let batch = messages
+      .iter()
+      .map(|message| Self::make_request(message.as_ref()))
+      .collect::<Result<Vec<_>>>()?;
 
-    for (message, response_protobuf_type) in messages
-      .into_iter()
-      .zip(response_protobuf_types.into_iter())
-    {
-      let protobuf_type = message
-        .descriptor_dyn()
-        .proto()
-        .options
-        .as_ref()
-        .and_then(|options| proto::api_options::exts::id.get(options))
-        .unwrap();
-      let protobuf_data = message.write_to_bytes_dyn().unwrap();
-      let (tx, rx) = tokio::sync::oneshot::channel();
-      let request_message = EspHomeMessage::new_request_with_await(
-        protobuf_type,
-        protobuf_data,
-        response_protobuf_type,
-        tx,
-      );
+    // Registering before sending, so a response can't arrive before its waiter
+    let receivers = {
+      let mut pending = self.pending.lock().unwrap();
+      response_protobuf_types
+        .into_iter()
+        .map(|response_protobuf_type| pending.register_once(response_protobuf_type))
+        .collect::<Vec<_>>()
+    };
 
-      channel_tx.send(request_message).await?;
+    // Everything is sent as one batch, the responses are collected as they arrive
+    outbound.send(batch).await?;
 
-      match timeout(Duration::from_secs(5), rx).await {
+    let mut responses = Vec::new();
+    for rx in receivers {
+      match timeout(timeout_duration, rx).await {
         Ok(Ok(message)) => {
-          responses.push(message);
+          responses.push(message?);
         }
         Ok(Err(_)) => {
-          return Err("Response channel closed".into());
+          return Err(Error::Disconnected(DisconnectReason::SocketClosed));
         }
         Err(_) => {
-          return Err("Timeout waiting for response".into());
+          return Err(Error::Timeout);
         }
       }
     }
     Ok(responses)
   }
 
+  /// Sends the message and collects every message of `response_protobuf_types` until
+  /// one of `until_protobuf_type` arrives, see [`Connection::send_message_stream_until`].
   pub async fn send_message_await_until(
     &self,
     message: Box<dyn protobuf::MessageDyn>,
@@ -507,106 +1029,198 @@ impl Connection {
     until_protobuf_type: u32,
     timeout_duration: Duration,
   ) -> Result<Vec<ProtobufMessage>> {
-    let channel_tx = self.channel_tx.clone().unwrap();
+    let stream = self
+      .send_message_stream_until(
+        message,
+        response_protobuf_types,
+        until_protobuf_type,
+        timeout_duration,
+      )
+      .await?;
+    let mut stream = std::pin::pin!(stream);
+
+    let mut responses = Vec::new();
+    while let Some(message) = stream.next().await {
+      responses.push(message?);
+    }
+    Ok(responses)
+  }
+
+  /// Sends the message and streams every message of `response_protobuf_types` as it
+  /// arrives. A message of `until_protobuf_type` ends the stream without being yielded.
+  ///
+  /// If the device stays silent for `timeout_duration` between two messages the stream
+  /// yields [`Error::Timeout`] and ends.
+  pub async fn send_message_stream_until(
+    &self,
+    message: Box<dyn protobuf::MessageDyn>,
+    response_protobuf_types: Vec<u32>,
+    until_protobuf_type: u32,
+    timeout_duration: Duration,
+  ) -> Result<impl Stream<Item = Result<ProtobufMessage>> + Send + 'static> {
+    let channel_tx = self.sender()?;
+
+    let request_message = Self::make_request(message.as_ref())?;
+    let rx = self
+      .pending
+      .lock()
+      .unwrap()
+      .register_until(&response_protobuf_types, until_protobuf_type);
 
-    let protobuf_type = message
-      .descriptor_dyn()
+    channel_tx.send(vec![request_message]).await?;
+
+    Ok(futures::stream::unfold(Some(rx), move |rx| async move {
+      let mut rx = rx?;
+      let result = match timeout(timeout_duration, rx.recv()).await {
+        Ok(Some(Ok(message))) if message.protobuf_type == until_protobuf_type => return None,
+        Ok(Some(Ok(message))) => return Some((Ok(message), Some(rx))),
+        Ok(Some(Err(e))) => Err(e),
+        Ok(None) => Err(Error::Disconnected(DisconnectReason::SocketClosed)),
+        Err(_) => Err(Error::Timeout),
+      };
+      // The stream ends after the first error
+      Some((result, None))
+    }))
+  }
+
+  /// Returns the sender of the running session's outbound queue, once it is logged in.
+  fn sender(&self) -> Result<Outbound> {
+    if !self.is_connected() {
+      return Err(Error::NotConnected);
+    }
+    self
+      .channel_tx
+      .read()
+      .unwrap()
+      .clone()
+      .ok_or(Error::NotConnected)
+  }
+
+  /// Returns the id the message is sent with, from its `id` option.
+  fn message_id(descriptor: &MessageDescriptor) -> Result<u32> {
+    descriptor
       .proto()
       .options
       .as_ref()
       .and_then(|options| proto::api_options::exts::id.get(options))
-      .unwrap();
-    let protobuf_data = message.write_to_bytes_dyn().unwrap();
-    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-    let request_message = EspHomeMessage::new_request_with_await_multiple_until(
-      protobuf_type,
-      protobuf_data,
-      response_protobuf_types,
-      until_protobuf_type,
-      tx,
-    );
-
-    channel_tx.send(request_message).await?;
+      .ok_or_else(|| Error::InvalidRequest(format!("{} has no message id", descriptor.name())))
+  }
 
-    let mut responses = Vec::new();
+  fn make_request(message: &dyn protobuf::MessageDyn) -> Result<EspHomeMessage> {
+    let protobuf_type = Self::message_id(&message.descriptor_dyn())?;
+    let protobuf_data = message.write_to_bytes_dyn()?;
+    Ok(EspHomeMessage::new_request(protobuf_type, protobuf_data))
+  }
 
-    while let Ok(Some(message)) = timeout(timeout_duration, rx.recv()).await {
-      responses.push(message);
-    }
+  /// Streams every message of `protobuf_types` received from now on, across reconnects.
+  ///
+  /// Dropping the returned [`Subscription`] unsubscribes.
+  pub fn subscribe(&self, protobuf_types: &[u32]) -> Subscription {
+    self.subscriptions.lock().unwrap().subscribe(protobuf_types)
+  }
 
-    Ok(responses)
+  /// Like [`Connection::subscribe`] for a single message type, yielding the parsed messages.
+  pub fn subscribe_typed<M: MessageFull>(&self) -> Result<TypedSubscription<M>> {
+    let protobuf_type = Self::message_id(&M::descriptor())?;
+    Ok(TypedSubscription::new(self.subscribe(&[protobuf_type])))
   }
 
-  pub fn add_message_handler(&mut self, msg_type: u32, handler: Callback, remove_after_call: bool) {
-    self
+  /// Calls `handler` for every message of `msg_type`, or only for the next one if
+  /// `remove_after_call` is set.
+  ///
+  /// The handler is removed when the returned guard is dropped, see [`HandlerGuard::detach`].
+  pub fn add_message_handler(
+    &self,
+    msg_type: u32,
+    handler: Callback,
+    remove_after_call: bool,
+  ) -> HandlerGuard {
+    let id = self
       .message_handlers
       .write()
       .unwrap()
-      .entry(msg_type)
-      .or_insert_with(Vec::new)
-      .push((remove_after_call, handler));
+      .add(msg_type, handler, remove_after_call);
+    HandlerGuard::new(id, &self.message_handlers)
+  }
+
+  /// Removes a handler, returns `false` if it was already removed.
+  pub fn remove_message_handler(&self, id: HandlerId) -> bool {
+    self.message_handlers.write().unwrap().remove(id)
   }
 
   fn make_hello_request(&self) -> proto::api::HelloRequest {
-    let mut request = proto::api::HelloRequest::default();
-    request.client_info = self.client_info.clone();
-    request.api_version_major = 1;
-    request.api_version_minor = 10;
-    request
+    proto::api::HelloRequest {
+      client_info: self.options.client_info.clone(),
+      api_version_major: API_VERSION_MAJOR,
+      api_version_minor: API_VERSION_MINOR,
+      ..Default::default()
+    }
   }
 
   fn make_connect_request(&self) -> proto::api::ConnectRequest {
     let mut request = proto::api::ConnectRequest::default();
-    if self.password.is_some() {
-      request.password = self.password.clone().unwrap();
+    if let Some(password) = &self.options.password {
+      request.password = password.clone();
     };
     request
   }
 
-  fn keep_alive(&mut self, duration: Duration) -> JoinHandle<()> {
-    let tx = self.channel_tx.clone().unwrap();
-    let (protobuf_type, request) = proto::api::PingRequest::create_message_with_type();
-    let protobuf_data = request.write_to_bytes().unwrap();
-
-    let ping_response_protobuf_type = proto::api::PingResponse::get_option_id();
-    self.add_message_handler(
-      ping_response_protobuf_type,
-      Box::new(move |_, message| {
-        let response = proto::api::PingResponse::parse_from_bytes(&message.protobuf_data).unwrap();
-        println!("Received PingResponse: {:?}", response);
-        Ok(())
-      }),
-      false,
-    );
+  /// Pings the device every keep-alive interval and closes the session when
+  /// too many pings in a row went unanswered.
+  fn keep_alive(
+    &self,
+    outbound: Outbound,
+    close_tx: mpsc::UnboundedSender<DisconnectReason>,
+  ) -> JoinHandle<()> {
+    let duration = self.options.keep_alive;
+    let max_missed_pings = self.options.max_missed_pings;
+    let outstanding_pings = self.outstanding_pings.clone();
+    outstanding_pings.store(0, Ordering::SeqCst);
 
-    tokio::spawn(async move {
-      let mut interval = tokio::time::interval(duration);
-      loop {
-        interval.tick().await;
-        println!("Sending PingRequest");
-        if let Err(err) = tx
-          .send(EspHomeMessage::new_request(
-            protobuf_type,
-            protobuf_data.clone(),
-          ))
-          .await
-        {
-          println!("Error sending PingRequest: {:?}", err);
+    tokio::spawn(
+      async move {
+        let mut interval = tokio::time::interval(duration);
+        loop {
+          interval.tick().await;
+          let outstanding = outstanding_pings.fetch_add(1, Ordering::SeqCst);
+          if outstanding >= max_missed_pings {
+            warn!(missed = outstanding, "device stopped answering pings");
+            let _ = close_tx.send(DisconnectReason::KeepAliveTimeout);
+            break;
+          }
+          let request = match Self::make_request(&proto::api::PingRequest::new()) {
+            Ok(request) => request,
+            Err(e) => {
+              let _ = close_tx.send(DisconnectReason::from(&e));
+              break;
+            }
+          };
+          // Never wait on the queue, a writer stuck on a dead peer would block the check above
+          match outbound.try_send(vec![request]) {
+            Ok(true) => outbound.metrics.ping_sent(),
+            Ok(false) => debug!("outbound queue full, counting the ping as missed"),
+            Err(err) => warn!(error = %err, "sending PingRequest failed"),
+          }
         }
       }
-    })
+      .in_current_span(),
+    )
   }
 
   fn handle_disconnect_request(connection: Arc<RwLock<Self>>, _: ProtobufMessage) -> Result<()> {
-    let mut connection = connection.write().unwrap();
-    connection.state = ConnectionState::Closed;
+    let connection = connection.read().unwrap();
+    // The device closes the socket once it got the response
+    connection.remote_disconnect.store(true, Ordering::SeqCst);
     let message = proto::api::DisconnectResponse::default();
     let connection = connection.clone();
-    tokio::spawn(async move {
-      if let Err(e) = connection.send_message(Box::new(message)).await {
-        println!("Error sending message: {:?}", e);
+    tokio::spawn(
+      async move {
+        if let Err(e) = connection.send(message).await {
+          warn!(error = %e, "sending response failed");
+        }
       }
-    });
+      .in_current_span(),
+    );
     Ok(())
   }
 
@@ -614,11 +1228,22 @@ impl Connection {
     let connection = connection.read().unwrap();
     let message = proto::api::PingResponse::default();
     let connection = connection.clone();
-    tokio::spawn(async move {
-      if let Err(e) = connection.send_message(Box::new(message)).await {
-        println!("Error sending message: {:?}", e);
+    tokio::spawn(
+      async move {
+        if let Err(e) = connection.send(message).await {
+          warn!(error = %e, "sending response failed");
+        }
       }
-    });
+      .in_current_span(),
+    );
+    Ok(())
+  }
+
+  fn handle_ping_response(connection: Arc<RwLock<Self>>, message: ProtobufMessage) -> Result<()> {
+    proto::api::PingResponse::parse_from_bytes(&message.protobuf_data)?;
+    let connection = connection.read().unwrap();
+    connection.outstanding_pings.store(0, Ordering::SeqCst);
+    connection.metrics.pong_received();
     Ok(())
   }
 
@@ -627,14 +1252,28 @@ impl Connection {
     let mut response = proto::api::GetTimeResponse::new();
     response.epoch_seconds = SystemTime::now()
       .duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap()
+      .unwrap_or_default()
       .as_secs() as u32;
     let connection = connection.clone();
-    tokio::spawn(async move {
-      if let Err(e) = connection.send_message(Box::new(response)).await {
-        println!("Error sending message: {:?}", e);
+    tokio::spawn(
+      async move {
+        if let Err(e) = connection.send(response).await {
+          warn!(error = %e, "sending response failed");
+        }
       }
-    });
+      .in_current_span(),
+    );
     Ok(())
   }
 }
+
+/// Logs a frame going `direction`, the payload is only included at trace level.
+fn log_message(direction: &'static str, message: &ProtobufMessage) {
+  let name = message_name(message.protobuf_type);
+  let size = message.protobuf_data.len();
+  if tracing::enabled!(Level::TRACE) {
+    trace!(message = name, direction, size, payload = ?message.protobuf_data, "frame");
+  } else {
+    debug!(message = name, direction, size, "frame");
+  }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use tokio::sync::{mpsc, oneshot};
+
+use super::{DisconnectReason, ProtobufMessage};
+use crate::{Error, Result};
+
+enum Waiter {
+  /// Completed by the first message of the type it was registered for
+  Once(oneshot::Sender<Result<ProtobufMessage>>),
+  /// Receives every message of its response types, ends with the message of the until type
+  Until {
+    id: u64,
+    until_protobuf_type: u32,
+    tx: mpsc::UnboundedSender<Result<ProtobufMessage>>,
+  },
+}
+
+impl Waiter {
+  fn is_closed(&self) -> bool {
+    match self {
+      Waiter::Once(tx) => tx.is_closed(),
+      Waiter::Until { tx, .. } => tx.is_closed(),
+    }
+  }
+}
+
+/// Requests waiting for a response, keyed by the expected response type.
+///
+/// The device answers requests of the same kind in order, so every response
+/// is handed to the oldest waiter registered for its type. Waiters of other
+/// types are never blocked by it.
+#[derive(Default)]
+pub struct PendingRequests {
+  next_id: u64,
+  waiters: HashMap<u32, VecDeque<Waiter>>,
+}
+
+impl PendingRequests {
+  /// Registers a waiter for a single message of `response_protobuf_type`.
+  pub fn register_once(
+    &mut self,
+    response_protobuf_type: u32,
+  ) -> oneshot::Receiver<Result<ProtobufMessage>> {
+    self.prune();
+    let (tx, rx) = oneshot::channel();
+    self
+      .waiters
+      .entry(response_protobuf_type)
+      .or_default()
+      .push_back(Waiter::Once(tx));
+    rx
+  }
+
+  /// Registers a waiter for every message of `response_protobuf_types` until a message of
+  /// `until_protobuf_type` arrives. The until message is the last one sent to the receiver.
+  pub fn register_until(
+    &mut self,
+    response_protobuf_types: &[u32],
+    until_protobuf_type: u32,
+  ) -> mpsc::UnboundedReceiver<Result<ProtobufMessage>> {
+    self.prune();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let id = self.next_id;
+    self.next_id += 1;
+
+    for protobuf_type in response_protobuf_types
+      .iter()
+      .chain(std::iter::once(&until_protobuf_type))
+    {
+      self
+        .waiters
+        .entry(*protobuf_type)
+        .or_default()
+        .push_back(Waiter::Until {
+          id,
+          until_protobuf_type,
+          tx: tx.clone(),
+        });
+    }
+    rx
+  }
+
+  /// Drops the waiters whose caller gave up, a response that never comes would otherwise
+  /// keep them queued for the life of the connection.
+  fn prune(&mut self) {
+    self.waiters.retain(|_, queue| {
+      queue.retain(|waiter| !waiter.is_closed());
+      !queue.is_empty()
+    });
+  }
+
+  /// Hands the message to the oldest waiter registered for its type.
+  pub fn dispatch(&mut self, message: &ProtobufMessage) {
+    let Some(queue) = self.waiters.get_mut(&message.protobuf_type) else {
+      return;
+    };
+
+    // Waiters whose caller gave up (e.g. timed out) are skipped
+    while queue.front().is_some_and(Waiter::is_closed) {
+      queue.pop_front();
+    }
+
+    let finished = match queue.front() {
+      Some(Waiter::Until {
+        id,
+        until_protobuf_type,
+        tx,
+      }) => {
+        let _ = tx.send(Ok(message.clone()));
+        (*until_protobuf_type == message.protobuf_type).then_some(*id)
+      }
+      Some(Waiter::Once(_)) => {
+        if let Some(Waiter::Once(tx)) = queue.pop_front() {
+          let _ = tx.send(Ok(message.clone()));
+        }
+        None
+      }
+      None => None,
+    };
+
+    if let Some(finished) = finished {
+      for queue in self.waiters.values_mut() {
+        queue.retain(|waiter| !matches!(waiter, Waiter::Until { id, .. } if *id == finished));
+      }
+    }
+  }
+
+  /// Fails every waiter with [`Error::Disconnected`].
+  pub fn close(&mut self, reason: &DisconnectReason) {
+    let mut closed_ids = HashSet::new();
+    for (_, queue) in self.waiters.drain() {
+      for waiter in queue {
+        match waiter {
+          Waiter::Once(tx) => {
+            let _ = tx.send(Err(Error::Disconnected(reason.clone())));
+          }
+          Waiter::Until { id, tx, .. } => {
+            if closed_ids.insert(id) {
+              let _ = tx.send(Err(Error::Disconnected(reason.clone())));
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(protobuf_type: u32, byte: u8) -> ProtobufMessage {
+    ProtobufMessage {
+      protobuf_type,
+      protobuf_data: vec![byte],
+    }
+  }
+
+  fn data(received: Result<ProtobufMessage>) -> (u32, Vec<u8>) {
+    let message = received.unwrap();
+    (message.protobuf_type, message.protobuf_data)
+  }
+
+  #[test]
+  fn delivers_in_order_per_type() {
+    let mut pending = PendingRequests::default();
+    let mut first = pending.register_once(1);
+    let mut other = pending.register_once(2);
+    let mut second = pending.register_once(1);
+
+    pending.dispatch(&message(1, 0xa));
+    pending.dispatch(&message(1, 0xb));
+    assert_eq!(data(first.try_recv().unwrap()), (1, vec![0xa]));
+    assert_eq!(data(second.try_recv().unwrap()), (1, vec![0xb]));
+    // A response of another type doesn't wait for the ones before it
+    assert!(other.try_recv().is_err());
+
+    pending.dispatch(&message(2, 0xc));
+    assert_eq!(data(other.try_recv().unwrap()), (2, vec![0xc]));
+  }
+
+  #[test]
+  fn skips_closed_waiters() {
+    let mut pending = PendingRequests::default();
+    let timed_out = pending.register_once(1);
+    let mut waiting = pending.register_once(1);
+    drop(timed_out);
+
+    pending.dispatch(&message(1, 0xa));
+    assert_eq!(data(waiting.try_recv().unwrap()), (1, vec![0xa]));
+    assert!(pending.waiters[&1].is_empty());
+  }
+
+  #[test]
+  fn registering_drops_abandoned_waiters() {
+    let mut pending = PendingRequests::default();
+    for _ in 0..10 {
+      drop(pending.register_once(1));
+      drop(pending.register_until(&[2], 3));
+    }
+    let _waiting = pending.register_once(1);
+
+    assert_eq!(pending.waiters.len(), 1);
+    assert_eq!(pending.waiters[&1].len(), 1);
+  }
+
+  #[test]
+  fn until_waiter_ends_on_until_type() {
+    let mut pending = PendingRequests::default();
+    let mut rx = pending.register_until(&[1, 2], 3);
+
+    pending.dispatch(&message(1, 0xa));
+    pending.dispatch(&message(2, 0xb));
+    pending.dispatch(&message(3, 0xc));
+    assert_eq!(data(rx.try_recv().unwrap()), (1, vec![0xa]));
+    assert_eq!(data(rx.try_recv().unwrap()), (2, vec![0xb]));
+    assert_eq!(data(rx.try_recv().unwrap()), (3, vec![0xc]));
+
+    // Removed from the queues of every type it was registered for
+    assert!(pending.waiters.values().all(VecDeque::is_empty));
+    pending.dispatch(&message(1, 0xd));
+    assert!(rx.try_recv().is_err());
+  }
+
+  #[test]
+  fn until_waiters_are_served_in_order() {
+    let mut pending = PendingRequests::default();
+    let mut first = pending.register_until(&[1], 2);
+    let mut second = pending.register_until(&[1], 2);
+
+    pending.dispatch(&message(1, 0xa));
+    pending.dispatch(&message(2, 0xb));
+    pending.dispatch(&message(1, 0xc));
+    assert_eq!(data(first.try_recv().unwrap()), (1, vec![0xa]));
+    assert_eq!(data(first.try_recv().unwrap()), (2, vec![0xb]));
+    assert!(first.try_recv().is_err());
+    assert_eq!(data(second.try_recv().unwrap()), (1, vec![0xc]));
+  }
+
+  #[test]
+  fn close_fails_every_waiter_once() {
+    let mut pending = PendingRequests::default();
+    let mut once = pending.register_once(1);
+    let mut until = pending.register_until(&[1, 2], 3);
+
+    pending.close(&DisconnectReason::SocketClosed);
+    assert!(matches!(
+      once.try_recv().unwrap(),
+      Err(Error::Disconnected(DisconnectReason::SocketClosed))
+    ));
+    assert!(matches!(
+      until.try_recv().unwrap(),
+      Err(Error::Disconnected(DisconnectReason::SocketClosed))
+    ));
+    // Registered for three types, but failed only once and then closed
+    assert!(matches!(
+      until.try_recv(),
+      Err(mpsc::error::TryRecvError::Disconnected)
+    ));
+    assert!(pending.waiters.is_empty());
+  }
+}
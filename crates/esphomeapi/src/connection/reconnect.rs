@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use rand::Rng as _;
+
+/// Controls how a dropped connection is re-established.
+///
+/// The delay before attempt `n` is `initial_delay * multiplier^(n - 1)`, capped at
+/// `max_delay` and spread randomly by `jitter` so that many devices dropping at
+/// the same time don't reconnect in lockstep.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+  /// Delay before the first reconnect attempt
+  pub initial_delay: Duration,
+  /// Upper bound for the delay between two attempts
+  pub max_delay: Duration,
+  /// Factor the delay grows by after every failed attempt
+  pub multiplier: f64,
+  /// Random spread applied to every delay, as a fraction of it (0.0 - 1.0)
+  pub jitter: f64,
+  /// Give up after this many failed attempts, `None` retries forever
+  pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    ReconnectPolicy {
+      initial_delay: Duration::from_secs(1),
+      max_delay: Duration::from_secs(60),
+      multiplier: 2.0,
+      jitter: 0.2,
+      max_attempts: None,
+    }
+  }
+}
+
+impl ReconnectPolicy {
+  /// Returns the delay to wait before the given (1-based) attempt.
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let base = (self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent))
+      .min(self.max_delay.as_secs_f64());
+
+    let jitter = self.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 {
+      rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+    } else {
+      1.0
+    };
+
+    Duration::from_secs_f64(base * factor)
+  }
+}
+
+/// Progress of re-establishing a dropped connection.
+#[derive(Clone, Debug)]
+pub enum ReconnectEvent {
+  /// The connection was lost, the given attempt starts after `delay`
+  Scheduled { attempt: u32, delay: Duration },
+  /// The attempt succeeded, handshake and hello/login were completed again
+  Connected { attempt: u32 },
+  /// The attempt failed, another one will be scheduled if the policy allows it
+  Failed { attempt: u32, error: crate::Error },
+  /// `max_attempts` was reached, the connection stays closed
+  GaveUp { attempts: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(jitter: f64) -> ReconnectPolicy {
+    ReconnectPolicy {
+      initial_delay: Duration::from_millis(100),
+      max_delay: Duration::from_secs(1),
+      multiplier: 2.0,
+      jitter,
+      max_attempts: None,
+    }
+  }
+
+  #[test]
+  fn grows_by_the_multiplier() {
+    let policy = policy(0.0);
+    assert_eq!(policy.delay(1), Duration::from_millis(100));
+    assert_eq!(policy.delay(2), Duration::from_millis(200));
+    assert_eq!(policy.delay(3), Duration::from_millis(400));
+    assert_eq!(policy.delay(4), Duration::from_millis(800));
+  }
+
+  #[test]
+  fn is_capped_at_max_delay() {
+    let policy = policy(0.0);
+    assert_eq!(policy.delay(5), Duration::from_secs(1));
+    assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn never_shrinks_with_a_multiplier_below_one() {
+    let policy = ReconnectPolicy {
+      multiplier: 0.5,
+      ..policy(0.0)
+    };
+    assert_eq!(policy.delay(3), Duration::from_millis(100));
+  }
+
+  #[test]
+  fn jitter_stays_within_its_fraction() {
+    let policy = policy(0.2);
+    for _ in 0..1000 {
+      let delay = policy.delay(2);
+      assert!(delay >= Duration::from_millis(160), "{:?}", delay);
+      assert!(delay <= Duration::from_millis(240), "{:?}", delay);
+    }
+    // The cap applies before the spread, so a capped delay can still exceed max_delay
+    let delay = policy.delay(10);
+    assert!(delay >= Duration::from_millis(800) && delay <= Duration::from_millis(1200));
+  }
+}
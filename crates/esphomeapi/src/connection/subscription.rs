@@ -0,0 +1,200 @@
+use std::{
+  collections::HashMap,
+  marker::PhantomData,
+  pin::Pin,
+  sync::Arc,
+  task::{Context, Poll},
+};
+
+use futures::{stream::SelectAll, Stream};
+use protobuf::MessageFull;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::warn;
+
+use super::{metrics::Metrics, ProtobufMessage};
+use crate::Result;
+
+/// Broadcast channels of the subscribed message types.
+///
+/// A channel is created by the first subscriber of its type and removed once the last one
+/// was dropped, so unsubscribed types cost nothing on the receive path.
+pub struct Subscriptions {
+  /// Messages buffered per type for a subscriber that falls behind
+  capacity: usize,
+  channels: HashMap<u32, broadcast::Sender<ProtobufMessage>>,
+  metrics: Arc<Metrics>,
+}
+
+impl Subscriptions {
+  pub fn new(capacity: usize, metrics: Arc<Metrics>) -> Self {
+    Subscriptions {
+      capacity: capacity.max(1),
+      channels: HashMap::new(),
+      metrics,
+    }
+  }
+
+  pub fn subscribe(&mut self, protobuf_types: &[u32]) -> Subscription {
+    let mut streams = SelectAll::new();
+    for protobuf_type in protobuf_types {
+      let rx = self
+        .channels
+        .entry(*protobuf_type)
+        .or_insert_with(|| broadcast::channel(self.capacity).0)
+        .subscribe();
+      streams.push(BroadcastStream::new(rx));
+    }
+    Subscription {
+      streams,
+      dropped: 0,
+      metrics: self.metrics.clone(),
+    }
+  }
+
+  /// Hands the message to every subscriber of its type.
+  pub fn dispatch(&mut self, message: &ProtobufMessage) {
+    if let Some(tx) = self.channels.get(&message.protobuf_type) {
+      // Sending only fails once every subscriber was dropped
+      if tx.send(message.clone()).is_err() {
+        self.channels.remove(&message.protobuf_type);
+      }
+    }
+  }
+}
+
+/// Stream of the messages of the subscribed types, received from the moment of subscribing.
+///
+/// Survives reconnects and unsubscribes when dropped. A subscriber that doesn't keep up
+/// loses the oldest messages, see [`Subscription::dropped`].
+pub struct Subscription {
+  streams: SelectAll<BroadcastStream<ProtobufMessage>>,
+  dropped: u64,
+  metrics: Arc<Metrics>,
+}
+
+impl Subscription {
+  /// Number of messages skipped so far because the subscriber fell behind.
+  pub fn dropped(&self) -> u64 {
+    self.dropped
+  }
+}
+
+impl Stream for Subscription {
+  type Item = ProtobufMessage;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    loop {
+      match Pin::new(&mut self.streams).poll_next(cx) {
+        Poll::Ready(Some(Ok(message))) => return Poll::Ready(Some(message)),
+        Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(skipped)))) => {
+          self.dropped += skipped;
+          self.metrics.dropped(skipped);
+          warn!(
+            skipped,
+            "subscriber is falling behind, messages were dropped"
+          );
+        }
+        Poll::Ready(None) => return Poll::Ready(None),
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+}
+
+/// [`Subscription`] to a single message type, yielding the parsed messages.
+pub struct TypedSubscription<M> {
+  inner: Subscription,
+  _message: PhantomData<fn() -> M>,
+}
+
+impl<M> TypedSubscription<M> {
+  pub(crate) fn new(inner: Subscription) -> Self {
+    TypedSubscription {
+      inner,
+      _message: PhantomData,
+    }
+  }
+
+  /// Number of messages skipped so far because the subscriber fell behind.
+  pub fn dropped(&self) -> u64 {
+    self.inner.dropped()
+  }
+}
+
+impl<M: MessageFull> Stream for TypedSubscription<M> {
+  type Item = Result<M>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    Pin::new(&mut self.inner)
+      .poll_next(cx)
+      .map(|message| message.map(|message| Ok(M::parse_from_bytes(&message.protobuf_data)?)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::{FutureExt as _, StreamExt as _};
+
+  use super::*;
+
+  fn message(protobuf_type: u32, byte: u8) -> ProtobufMessage {
+    ProtobufMessage {
+      protobuf_type,
+      protobuf_data: vec![byte],
+    }
+  }
+
+  fn next(subscription: &mut Subscription) -> Option<(u32, Vec<u8>)> {
+    let message = subscription.next().now_or_never()??;
+    Some((message.protobuf_type, message.protobuf_data))
+  }
+
+  #[test]
+  fn delivers_to_every_subscriber() {
+    let mut subscriptions = Subscriptions::new(8, Arc::default());
+    let mut first = subscriptions.subscribe(&[1]);
+    let mut second = subscriptions.subscribe(&[1, 2]);
+
+    subscriptions.dispatch(&message(1, 0xa));
+    subscriptions.dispatch(&message(2, 0xb));
+    subscriptions.dispatch(&message(3, 0xc));
+    assert_eq!(next(&mut first), Some((1, vec![0xa])));
+    assert_eq!(next(&mut first), None);
+    assert_eq!(next(&mut second), Some((1, vec![0xa])));
+    assert_eq!(next(&mut second), Some((2, vec![0xb])));
+    assert_eq!(next(&mut second), None);
+  }
+
+  #[test]
+  fn removes_the_channel_after_the_last_subscriber() {
+    let mut subscriptions = Subscriptions::new(8, Arc::default());
+    let first = subscriptions.subscribe(&[1]);
+    let second = subscriptions.subscribe(&[1]);
+
+    drop(first);
+    subscriptions.dispatch(&message(1, 0xa));
+    assert!(subscriptions.channels.contains_key(&1));
+
+    drop(second);
+    subscriptions.dispatch(&message(1, 0xb));
+    assert!(subscriptions.channels.is_empty());
+  }
+
+  #[test]
+  fn counts_the_messages_a_lagging_subscriber_missed() {
+    let metrics = Arc::new(Metrics::default());
+    let mut subscriptions = Subscriptions::new(2, metrics.clone());
+    let mut subscription = subscriptions.subscribe(&[1]);
+
+    for byte in 0..5 {
+      subscriptions.dispatch(&message(1, byte));
+    }
+    // Only the newest messages fitting the capacity are left
+    assert_eq!(next(&mut subscription), Some((1, vec![3])));
+    assert_eq!(next(&mut subscription), Some((1, vec![4])));
+    assert_eq!(next(&mut subscription), None);
+    assert_eq!(subscription.dropped(), 3);
+    assert_eq!(metrics.snapshot().dropped, 3);
+  }
+}
@@ -7,6 +7,8 @@ use std::{
 
 use mdns_sd::{ServiceDaemon, ServiceEvent};
 
+use crate::Result;
+
 const SERVICE_NAME: &str = "_esphomelib._tcp.local.";
 
 #[derive(Clone, Debug)]
@@ -27,42 +29,42 @@ pub struct ServiceInfo {
   pub weight: u16,
 }
 
-pub async fn discover(seconds: u32) -> Vec<ServiceInfo> {
-  let mdns = ServiceDaemon::new().unwrap();
-  let receiver = mdns.browse(SERVICE_NAME).expect("Failed to browse");
+/// Browses for ESPHome devices on the local network for `seconds`.
+pub async fn discover(seconds: u32) -> Result<Vec<ServiceInfo>> {
+  let mdns = ServiceDaemon::new()?;
+  let receiver = mdns.browse(SERVICE_NAME)?;
 
   let found_services = Arc::new(RwLock::new(HashMap::new()));
   let found_services_clone = found_services.clone();
 
   tokio::select! {
-    _ = tokio::time::sleep(Duration::from_secs(seconds as u64)) => mdns.shutdown().unwrap(),
+    _ = tokio::time::sleep(Duration::from_secs(seconds as u64)) => {}
     _ = async move {
-      loop {
-        match receiver.recv_async().await {
-          Ok(ServiceEvent::ServiceResolved(info)) => {
-            let mut write_guard = found_services_clone.write().unwrap();
-                    write_guard.insert(
-                      info.get_fullname().to_owned(),
-                      ServiceInfo {
-                        ty_domain: info.get_type().to_owned(),
-                        sub_domain: info.get_subtype().to_owned(),
-                        fullname: info.get_fullname().to_owned(),
-                        server: info.get_hostname().to_owned(),
-                        addresses: info.get_addresses().clone(),
-                        port: info.get_port(),
-                        host_ttl: info.get_host_ttl(),
-                        other_ttl: info.get_other_ttl(),
-                        priority: info.get_priority(),
-                        weight: info.get_weight(),
-                      },
-                    );
-          }
-          _ => { }
+      // Ends when the daemon stops
+      while let Ok(event) = receiver.recv_async().await {
+        if let ServiceEvent::ServiceResolved(info) = event {
+          let mut write_guard = found_services_clone.write().unwrap();
+          write_guard.insert(
+            info.get_fullname().to_owned(),
+            ServiceInfo {
+              ty_domain: info.get_type().to_owned(),
+              sub_domain: info.get_subtype().to_owned(),
+              fullname: info.get_fullname().to_owned(),
+              server: info.get_hostname().to_owned(),
+              addresses: info.get_addresses().clone(),
+              port: info.get_port(),
+              host_ttl: info.get_host_ttl(),
+              other_ttl: info.get_other_ttl(),
+              priority: info.get_priority(),
+              weight: info.get_weight(),
+            },
+          );
         }
       }
-    } => mdns.shutdown().unwrap(),
+    } => {}
   };
+  mdns.shutdown()?;
 
   let services = found_services.read().unwrap();
-  services.values().cloned().collect()
+  Ok(services.values().cloned().collect())
 }
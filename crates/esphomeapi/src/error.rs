@@ -0,0 +1,125 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+  model::{EntityInfo, UserService},
+  DisconnectReason,
+};
+
+/// Everything that can go wrong while talking to a device.
+///
+/// Cheap to clone, so it can be handed to every subscriber of the connection events.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Error {
+  /// Opening, reading or writing the socket failed
+  Io(Arc<std::io::Error>),
+  /// The device didn't answer in time
+  Timeout,
+  /// The device stopped sending entities before `ListEntitiesDoneResponse`,
+  /// holds everything listed until then
+  ListEntitiesTimeout {
+    entities: Vec<EntityInfo>,
+    services: Vec<UserService>,
+  },
+  /// The device rejected the password sent in `ConnectRequest`
+  InvalidPassword,
+  /// The device reported a different name than the expected one
+  ServerNameMismatch { expected: String, received: String },
+  /// The pre-shared key isn't a valid base64 encoded 32 byte key
+  InvalidPsk(String),
+  /// The device speaks a major API version this client doesn't understand
+  UnsupportedApiVersion { major: u32, minor: u32 },
+  /// The noise handshake couldn't be completed, holds the reason the device gave
+  HandshakeFailed(String),
+  /// The device rejected the handshake, its pre-shared key differs
+  PskRejected,
+  /// The device only accepts encrypted connections, but no pre-shared key is set
+  RequiresEncryption,
+  /// A pre-shared key is set, but the device doesn't use encryption
+  EncryptionNotSupported,
+  /// The device sent something that doesn't follow the protocol
+  Protocol(String),
+  /// A message couldn't be encoded or decoded
+  Protobuf(Arc<protobuf::Error>),
+  /// A message of a type that wasn't expected at this point was received
+  UnknownMessageType(u32),
+  /// The connection went away before the request was answered,
+  /// [`DisconnectReason::RemoteRequest`] means the device closed it
+  Disconnected(DisconnectReason),
+  /// The request was made before `connect()` or while the connection is down
+  NotConnected,
+  /// The request itself is invalid
+  InvalidRequest(String),
+  /// The mDNS daemon used for discovery failed
+  Discovery(Arc<mdns_sd::Error>),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Io(e) => write!(f, "socket error: {}", e),
+      Self::Timeout => write!(f, "timeout waiting for response"),
+      Self::ListEntitiesTimeout { entities, services } => write!(
+        f,
+        "timeout listing entities after {} entities and {} services",
+        entities.len(),
+        services.len()
+      ),
+      Self::InvalidPassword => write!(f, "invalid password"),
+      Self::ServerNameMismatch { expected, received } => write!(
+        f,
+        "server name mismatch, expected {} but got {}",
+        expected, received
+      ),
+      Self::InvalidPsk(reason) => write!(f, "invalid pre-shared key: {}", reason),
+      Self::UnsupportedApiVersion { major, minor } => {
+        write!(f, "unsupported API version {}.{}", major, minor)
+      }
+      Self::HandshakeFailed(reason) => write!(f, "handshake failed: {}", reason),
+      Self::PskRejected => write!(f, "the device rejected the pre-shared key"),
+      Self::RequiresEncryption => write!(f, "the device requires encryption, set a pre-shared key"),
+      Self::EncryptionNotSupported => write!(
+        f,
+        "the device doesn't use encryption, remove the pre-shared key"
+      ),
+      Self::Protocol(reason) => write!(f, "protocol error: {}", reason),
+      Self::Protobuf(e) => write!(f, "protobuf error: {}", e),
+      Self::UnknownMessageType(protobuf_type) => {
+        write!(f, "unknown message type: {}", protobuf_type)
+      }
+      Self::Disconnected(reason) => write!(f, "disconnected, {}", reason),
+      Self::NotConnected => write!(f, "not connected"),
+      Self::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+      Self::Discovery(e) => write!(f, "discovery error: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Io(e) => Some(e.as_ref()),
+      Self::Protobuf(e) => Some(e.as_ref()),
+      Self::Discovery(e) => Some(e.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(error: std::io::Error) -> Self {
+    Self::Io(Arc::new(error))
+  }
+}
+
+impl From<protobuf::Error> for Error {
+  fn from(error: protobuf::Error) -> Self {
+    Self::Protobuf(Arc::new(error))
+  }
+}
+
+impl From<mdns_sd::Error> for Error {
+  fn from(error: mdns_sd::Error) -> Self {
+    Self::Discovery(Arc::new(error))
+  }
+}
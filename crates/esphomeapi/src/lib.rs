@@ -7,12 +7,20 @@ pub use proto::api;
 mod client;
 mod connection;
 pub mod discovery;
+mod error;
 pub mod model;
+mod options;
 mod utils;
 
-pub use client::Client;
-pub use connection::Connection;
+pub use client::{Client, ListEntitiesItem};
+pub use connection::{
+  Callback, CodecFactory, Connection, ConnectionEvent, ConnectionMetrics, ConnectionState,
+  DisconnectReason, EspHomeCodec, EspHomeMessage, EspHomeMessageType, FrameCodec, HandlerGuard,
+  HandlerId, ProtobufMessage, Psk, ReconnectEvent, ReconnectPolicy, Subscription,
+  TypedSubscription,
+};
+pub use error::Error;
+pub use options::ClientOptions;
 pub use utils::Options;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -3,10 +3,13 @@ use std::collections::HashMap;
 use super::{entity_state::EntityState, EntityInfo};
 use crate::{proto::api, utils::Options as _, Result};
 
+type EntityInfoParser = fn(&[u8]) -> Result<EntityInfo>;
+type EntityStateParser = fn(&[u8]) -> Result<EntityState>;
+
 lazy_static::lazy_static! {
-    pub static ref LIST_ENTITIES_SERVICES_RESPONSE_TYPES: HashMap<u32, fn(&[u8]) -> Result<EntityInfo>> = {
+    pub static ref LIST_ENTITIES_SERVICES_RESPONSE_TYPES: HashMap<u32, EntityInfoParser> = {
         let mut m = HashMap::new();
-        m.insert(api::ListEntitiesAlarmControlPanelResponse::get_option_id(), EntityInfo::parse_alarm_control_panel as fn(&[u8]) -> Result<EntityInfo>);
+        m.insert(api::ListEntitiesAlarmControlPanelResponse::get_option_id(), EntityInfo::parse_alarm_control_panel as EntityInfoParser);
         m.insert(api::ListEntitiesBinarySensorResponse::get_option_id(), EntityInfo::parse_binary_sensor);
         m.insert(api::ListEntitiesButtonResponse::get_option_id(), EntityInfo::parse_button);
         m.insert(api::ListEntitiesCameraResponse::get_option_id(), EntityInfo::parse_camera);
@@ -31,9 +34,9 @@ lazy_static::lazy_static! {
         m
     };
 
-    pub static ref SUBCRIBE_STATES_RESPONSE_TYPES: HashMap<u32, fn(&[u8]) -> Result<EntityState>> = {
+    pub static ref SUBCRIBE_STATES_RESPONSE_TYPES: HashMap<u32, EntityStateParser> = {
         let mut m = HashMap::new();
-        m.insert(api::AlarmControlPanelStateResponse::get_option_id(), EntityState::parse_alarm_control_panel as fn(&[u8]) -> Result<EntityState>);
+        m.insert(api::AlarmControlPanelStateResponse::get_option_id(), EntityState::parse_alarm_control_panel as EntityStateParser);
         m.insert(api::BinarySensorStateResponse::get_option_id(), EntityState::parse_binary_sensor);
         m.insert(api::ClimateStateResponse::get_option_id(), EntityState::parse_climate);
         m.insert(api::CoverStateResponse::get_option_id(), EntityState::parse_cover);
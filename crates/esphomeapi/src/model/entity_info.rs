@@ -3,7 +3,7 @@ use protobuf::Message as _;
 use super::services;
 use crate::{api, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EntityInfo {
   AlarmControlPanel(services::AlarmControlPanelInfo),
   BinarySensor(services::BinarySensorInfo),
@@ -56,9 +56,7 @@ impl EntityState {
   pub fn parse_alarm_control_panel(data: &[u8]) -> Result<Self> {
     let data = api::AlarmControlPanelStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::AlarmControlPanel(
       services::AlarmControlPanelEntityState {
@@ -71,9 +69,7 @@ impl EntityState {
   pub fn parse_binary_sensor(data: &[u8]) -> Result<Self> {
     let data = api::BinarySensorStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::BinarySensor(services::BinarySensorState {
       entity_state,
@@ -85,9 +81,7 @@ impl EntityState {
   pub fn parse_climate(data: &[u8]) -> Result<Self> {
     let data = api::ClimateStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Climate(services::ClimateState {
       entity_state,
@@ -111,9 +105,7 @@ impl EntityState {
   pub fn parse_cover(data: &[u8]) -> Result<Self> {
     let data = api::CoverStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Cover(services::CoverState {
       entity_state,
@@ -127,9 +119,7 @@ impl EntityState {
   pub fn parse_date(data: &[u8]) -> Result<Self> {
     let data = api::DateStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Date(services::DateState {
       entity_state,
@@ -143,9 +133,7 @@ impl EntityState {
   pub fn parse_date_time(data: &[u8]) -> Result<Self> {
     let data = api::DateTimeStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::DateTime(services::DateTimeState {
       entity_state,
@@ -157,9 +145,7 @@ impl EntityState {
   pub fn parse_event(data: &[u8]) -> Result<Self> {
     let data = api::EventResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Event(services::Event {
       entity_state,
@@ -170,9 +156,7 @@ impl EntityState {
   pub fn parse_fan(data: &[u8]) -> Result<Self> {
     let data = api::FanStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Fan(services::FanState {
       entity_state,
@@ -187,9 +171,7 @@ impl EntityState {
   pub fn parse_light(data: &[u8]) -> Result<Self> {
     let data = api::LightStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Light(services::LightState {
       entity_state,
@@ -211,9 +193,7 @@ impl EntityState {
   pub fn parse_lock(data: &[u8]) -> Result<Self> {
     let data = api::LockStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Lock(services::LockEntityState {
       entity_state,
@@ -224,9 +204,7 @@ impl EntityState {
   pub fn parse_media_player(data: &[u8]) -> Result<Self> {
     let data = api::MediaPlayerStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::MediaPlayer(services::MediaPlayerEntityState {
       entity_state,
@@ -239,9 +217,7 @@ impl EntityState {
   pub fn parse_number(data: &[u8]) -> Result<Self> {
     let data = api::NumberStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Number(services::NumberState {
       entity_state,
@@ -253,9 +229,7 @@ impl EntityState {
   pub fn parse_select(data: &[u8]) -> Result<Self> {
     let data = api::SelectStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Select(services::SelectState {
       entity_state,
@@ -267,9 +241,7 @@ impl EntityState {
   pub fn parse_sensor(data: &[u8]) -> Result<Self> {
     let data = api::SensorStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Sensor(services::SensorState {
       entity_state,
@@ -281,9 +253,7 @@ impl EntityState {
   pub fn parse_switch(data: &[u8]) -> Result<Self> {
     let data = api::SwitchStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Switch(services::SwitchState {
       entity_state,
@@ -294,9 +264,7 @@ impl EntityState {
   pub fn parse_text(data: &[u8]) -> Result<Self> {
     let data = api::TextStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Text(services::TextState {
       entity_state,
@@ -308,9 +276,7 @@ impl EntityState {
   pub fn parse_text_sensor(data: &[u8]) -> Result<Self> {
     let data = api::TextSensorStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::TextSensor(services::TextSensorState {
       entity_state,
@@ -322,9 +288,7 @@ impl EntityState {
   pub fn parse_time(data: &[u8]) -> Result<Self> {
     let data = api::TimeStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Time(services::TimeState {
       entity_state,
@@ -338,9 +302,7 @@ impl EntityState {
   pub fn parse_update(data: &[u8]) -> Result<Self> {
     let data = api::UpdateStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Update(services::UpdateState {
       entity_state,
@@ -359,9 +321,7 @@ impl EntityState {
   pub fn parse_valve(data: &[u8]) -> Result<Self> {
     let data = api::ValveStateResponse::parse_from_bytes(data)?;
 
-    let entity_state = services::BaseEntityState {
-      key: data.key.clone(),
-    };
+    let entity_state = services::BaseEntityState { key: data.key };
 
     Ok(EntityState::Valve(services::ValveState {
       entity_state,
@@ -4,7 +4,7 @@ use enumflags2::{bitflags, BitFlags};
 
 use crate::{
   api::{self, DeviceInfoResponse},
-  proto,
+  proto, Error, Result,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -52,7 +52,7 @@ pub enum VoiceAssistantSubscriptionFlag {
   APIAudio = 1 << 0,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct DeviceInfo {
   pub uses_password: bool,
   pub name: String,
@@ -98,28 +98,6 @@ impl From<DeviceInfoResponse> for DeviceInfo {
 }
 
 impl DeviceInfo {
-  pub fn default() -> DeviceInfo {
-    DeviceInfo {
-      uses_password: false,
-      name: String::new(),
-      friendly_name: String::new(),
-      mac_address: String::new(),
-      compilation_time: String::new(),
-      model: String::new(),
-      manufacturer: String::new(),
-      has_deep_sleep: false,
-      esphome_version: String::new(),
-      project_name: String::new(),
-      project_version: String::new(),
-      webserver_port: 0,
-      legacy_voice_assistant_version: 0,
-      voice_assistant_feature_flags: 0,
-      legacy_bluetooth_proxy_version: 0,
-      bluetooth_proxy_feature_flags: 0,
-      suggested_area: String::new(),
-    }
-  }
-
   pub fn bluetooth_proxy_feature_flags_compat(&self, api_version: APIVersion) -> u32 {
     if api_version < APIVersion::new(1, 9) {
       let mut flags = BitFlags::empty();
@@ -140,7 +118,7 @@ impl DeviceInfo {
       }
       return flags.bits();
     }
-    return self.bluetooth_proxy_feature_flags;
+    self.bluetooth_proxy_feature_flags
   }
 
   pub fn voice_assistant_feature_flags_compat(&self, api_version: APIVersion) -> u32 {
@@ -154,7 +132,7 @@ impl DeviceInfo {
       }
       return flags.bits();
     }
-    return self.voice_assistant_feature_flags;
+    self.voice_assistant_feature_flags
   }
 }
 
@@ -281,7 +259,7 @@ impl CoverState {
     if api_version < APIVersion::new(1, 1) {
       return self.legacy_state == LegacyCoverState::Closed;
     }
-    return self.position == 0.0;
+    self.position == 0.0
   }
 }
 
@@ -397,9 +375,9 @@ impl From<proto::api::ColorMode> for ColorMode {
   }
 }
 
-impl Into<proto::api::ColorMode> for ColorMode {
-  fn into(self) -> proto::api::ColorMode {
-    match self {
+impl From<ColorMode> for proto::api::ColorMode {
+  fn from(val: ColorMode) -> Self {
+    match val {
       ColorMode::Unknown => proto::api::ColorMode::COLOR_MODE_UNKNOWN,
       ColorMode::OnOff => proto::api::ColorMode::COLOR_MODE_ON_OFF,
       ColorMode::Brightness => proto::api::ColorMode::COLOR_MODE_BRIGHTNESS,
@@ -414,9 +392,9 @@ impl Into<proto::api::ColorMode> for ColorMode {
   }
 }
 
-impl Into<u8> for ColorMode {
-  fn into(self) -> u8 {
-    self as u8
+impl From<ColorMode> for u8 {
+  fn from(val: ColorMode) -> Self {
+    val as u8
   }
 }
 
@@ -479,12 +457,12 @@ impl LightInfo {
 
       return vec![legacy_mode];
     }
-    return self
+    self
       .supported_color_modes
       .clone()
       .iter()
       .map(|x| (*x).into())
-      .collect();
+      .collect()
   }
 }
 
@@ -761,7 +739,7 @@ impl ClimateInfo {
       }
       return vec![];
     }
-    return self.supported_presets.clone();
+    self.supported_presets.clone()
   }
 }
 
@@ -792,7 +770,7 @@ impl ClimateState {
       }
       return ClimatePreset::Home;
     }
-    return self.preset;
+    self.preset
   }
 }
 
@@ -1319,12 +1297,26 @@ pub struct UserService {
 
 // ==================== BLUETOOTH ====================
 
-pub fn uuid_convert(uuid: String) -> String {
+pub fn uuid_convert(uuid: String) -> Result<String> {
   let mut uuid = uuid.to_lowercase();
   if uuid.len() < 8 {
-    uuid = format!("0000{}-0000-1000-8000-00805f9b34fb", uuid[2..].to_string());
+    // A short UUID like 0x180f
+    let short = uuid
+      .get(2..)
+      .ok_or_else(|| Error::Protocol(format!("invalid bluetooth uuid {:?}", uuid)))?;
+    uuid = format!("0000{}-0000-1000-8000-00805f9b34fb", short);
   }
-  return uuid;
+  Ok(uuid)
+}
+
+/// Parses a manufacturer id like 0x004c.
+fn parse_manufacturer_id(uuid: &str) -> Result<u16> {
+  let digits = uuid
+    .strip_prefix("0x")
+    .or_else(|| uuid.strip_prefix("0X"))
+    .unwrap_or(uuid);
+  u16::from_str_radix(digits, 16)
+    .map_err(|_| Error::Protocol(format!("invalid manufacturer id {:?}", uuid)))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -1339,7 +1331,7 @@ pub struct BluetoothLEAdvertisement {
 }
 
 impl BluetoothLEAdvertisement {
-  pub fn from_pb(data: proto::api::BluetoothLEAdvertisementResponse) -> Self {
+  pub fn from_pb(data: proto::api::BluetoothLEAdvertisementResponse) -> Result<Self> {
     let mut manufacturer_data: HashMap<u16, Vec<u8>> = HashMap::new();
     let mut service_data: HashMap<String, Vec<u8>> = HashMap::new();
     let mut service_uuids: Vec<String> = Vec::new();
@@ -1347,55 +1339,50 @@ impl BluetoothLEAdvertisement {
     let raw_manufacturer_data = data.manufacturer_data;
     if !raw_manufacturer_data.is_empty() {
       if !raw_manufacturer_data[0].data.is_empty() {
-        raw_manufacturer_data.iter().for_each(|item| {
-          manufacturer_data.insert(item.uuid.parse().unwrap(), item.data.clone());
-        });
+        for item in &raw_manufacturer_data {
+          manufacturer_data.insert(parse_manufacturer_id(&item.uuid)?, item.data.clone());
+        }
       } else {
         // legacy data
-        raw_manufacturer_data.iter().for_each(|item| {
+        for item in &raw_manufacturer_data {
           manufacturer_data.insert(
-            item.uuid.parse().unwrap(),
+            parse_manufacturer_id(&item.uuid)?,
             item
               .legacy_data
               .iter()
               .flat_map(|&num| num.to_le_bytes().to_vec())
               .collect(),
           );
-        });
+        }
       }
     }
 
     let raw_service_data = data.service_data;
     if !raw_service_data.is_empty() {
       if !raw_service_data[0].data.is_empty() {
-        raw_service_data.iter().for_each(|item| {
-          service_data.insert(uuid_convert(item.uuid.clone()), item.data.clone());
-        });
+        for item in &raw_service_data {
+          service_data.insert(uuid_convert(item.uuid.clone())?, item.data.clone());
+        }
       } else {
         // legacy data
-        raw_service_data.iter().for_each(|item| {
+        for item in &raw_service_data {
           service_data.insert(
-            uuid_convert(item.uuid.clone()),
+            uuid_convert(item.uuid.clone())?,
             item
               .legacy_data
               .iter()
               .flat_map(|&num| num.to_le_bytes().to_vec())
               .collect(),
           );
-        });
+        }
       }
     }
 
-    let raw_service_uuids = data.service_uuids;
-    if !raw_service_uuids.is_empty() {
-      service_uuids.extend(
-        raw_service_uuids
-          .iter()
-          .map(|uuid| uuid_convert(uuid.clone())),
-      );
+    for uuid in data.service_uuids {
+      service_uuids.push(uuid_convert(uuid)?);
     }
 
-    Self {
+    Ok(Self {
       address: data.address,
       rssi: data.rssi,
       address_type: data.address_type,
@@ -1403,7 +1390,7 @@ impl BluetoothLEAdvertisement {
       service_uuids,
       service_data,
       manufacturer_data,
-    }
+    })
   }
 }
 
@@ -0,0 +1,226 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use crate::{discovery::ServiceInfo, CodecFactory, FrameCodec, Psk, ReconnectPolicy};
+
+/// Settings of a connection to a single device.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use esphomeapi::{Client, ClientOptions};
+/// # fn main() -> esphomeapi::Result<()> {
+/// let options = ClientOptions::new("192.168.1.10")
+///   .psk("px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA=".parse()?)
+///   .request_timeout(Duration::from_secs(10));
+/// let client = Client::new(options);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ClientOptions {
+  pub(crate) address: String,
+  pub(crate) addresses: Vec<IpAddr>,
+  pub(crate) port: u32,
+  pub(crate) password: Option<String>,
+  pub(crate) expected_name: Option<String>,
+  pub(crate) psk: Option<Psk>,
+  pub(crate) plaintext_fallback: bool,
+  pub(crate) codec: Option<CodecFactory>,
+  pub(crate) client_info: String,
+  pub(crate) keep_alive: Duration,
+  pub(crate) max_missed_pings: u32,
+  pub(crate) connect_timeout: Duration,
+  pub(crate) handshake_timeout: Duration,
+  pub(crate) login_timeout: Duration,
+  pub(crate) request_timeout: Duration,
+  pub(crate) reconnect_policy: Option<ReconnectPolicy>,
+  pub(crate) tcp_keepalive: Option<Duration>,
+  pub(crate) tcp_nodelay: bool,
+  pub(crate) inbound_capacity: usize,
+  pub(crate) outbound_capacity: usize,
+  pub(crate) subscription_capacity: usize,
+}
+
+impl ClientOptions {
+  /// Options for the device at `address` with the defaults of the native API.
+  pub fn new(address: impl Into<String>) -> Self {
+    ClientOptions {
+      address: address.into(),
+      addresses: Vec::new(),
+      port: 6053,
+      password: None,
+      expected_name: None,
+      psk: None,
+      plaintext_fallback: false,
+      codec: None,
+      client_info: "esphome-rs".to_string(),
+      keep_alive: Duration::from_secs(20),
+      max_missed_pings: 4,
+      connect_timeout: Duration::from_secs(10),
+      handshake_timeout: Duration::from_secs(10),
+      login_timeout: Duration::from_secs(10),
+      request_timeout: Duration::from_secs(5),
+      reconnect_policy: Some(ReconnectPolicy::default()),
+      tcp_keepalive: None,
+      tcp_nodelay: true,
+      inbound_capacity: 32,
+      outbound_capacity: 32,
+      subscription_capacity: 64,
+    }
+  }
+
+  /// Addresses of the device, raced against each other on connect.
+  ///
+  /// When set, `address` isn't resolved and only names the device in logs.
+  pub fn addresses(mut self, addresses: impl IntoIterator<Item = IpAddr>) -> Self {
+    self.addresses = addresses.into_iter().collect();
+    self
+  }
+
+  /// Port of the native API, 6053 by default.
+  pub fn port(mut self, port: u32) -> Self {
+    self.port = port;
+    self
+  }
+
+  /// Password sent in `ConnectRequest`, only used by devices without encryption.
+  pub fn password(mut self, password: impl Into<String>) -> Self {
+    self.password = Some(password.into());
+    self
+  }
+
+  /// Refuses devices reporting another name, guards against a changed IP address.
+  pub fn expected_name(mut self, expected_name: impl Into<String>) -> Self {
+    self.expected_name = Some(expected_name.into());
+    self
+  }
+
+  /// Noise pre-shared key, enables the encrypted transport.
+  pub fn psk(mut self, psk: Psk) -> Self {
+    self.psk = Some(psk);
+    self
+  }
+
+  /// Connects without encryption when the device doesn't use it, even though a pre-shared
+  /// key is set. Off by default, such devices fail with
+  /// [`Error::EncryptionNotSupported`](crate::Error::EncryptionNotSupported).
+  ///
+  /// Only a plaintext answer to the encrypted hello counts, a device closing the connection
+  /// fails the handshake as usual.
+  ///
+  /// Devices requiring encryption always fail with
+  /// [`Error::RequiresEncryption`](crate::Error::RequiresEncryption) when no key is set.
+  pub fn plaintext_fallback(mut self, plaintext_fallback: bool) -> Self {
+    self.plaintext_fallback = plaintext_fallback;
+    self
+  }
+
+  /// Frames messages with the codecs `codec` makes, one for every session, instead of the
+  /// [`EspHomeCodec`](crate::EspHomeCodec) picked by `psk`.
+  ///
+  /// `psk` and `plaintext_fallback` are left to the codec then, e.g. a wrapper of
+  /// [`EspHomeCodec::noise`](crate::EspHomeCodec::noise).
+  pub fn codec<F>(mut self, codec: F) -> Self
+  where
+    F: Fn() -> Box<dyn FrameCodec> + Send + Sync + 'static,
+  {
+    self.codec = Some(Arc::new(codec));
+    self
+  }
+
+  /// Name the client introduces itself with in `HelloRequest`.
+  pub fn client_info(mut self, client_info: impl Into<String>) -> Self {
+    self.client_info = client_info.into();
+    self
+  }
+
+  /// Interval between two pings, 20 seconds by default. Zero disables the pings, a dead
+  /// device is then only noticed once the socket reports it.
+  pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+    self.keep_alive = keep_alive;
+    self
+  }
+
+  /// Number of unanswered pings after which the device is considered dead, 4 by default.
+  pub fn max_missed_pings(mut self, max_missed_pings: u32) -> Self {
+    self.max_missed_pings = max_missed_pings.max(1);
+    self
+  }
+
+  /// Time allowed for opening the socket, 10 seconds by default.
+  pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+    self.connect_timeout = connect_timeout;
+    self
+  }
+
+  /// Time allowed for the noise handshake, 10 seconds by default.
+  pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+    self.handshake_timeout = handshake_timeout;
+    self
+  }
+
+  /// Time allowed for each of `HelloRequest` and `ConnectRequest` to be answered,
+  /// 10 seconds by default.
+  pub fn login_timeout(mut self, login_timeout: Duration) -> Self {
+    self.login_timeout = login_timeout;
+    self
+  }
+
+  /// Time allowed for the device to answer a request, 5 seconds by default.
+  ///
+  /// Requests answered by a stream of messages apply it to the gap between two messages.
+  /// Single calls can override it with the `*_timeout` variants of the request methods.
+  pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+    self.request_timeout = request_timeout;
+    self
+  }
+
+  /// How a dropped connection is re-established, `None` disables reconnecting.
+  pub fn reconnect_policy(mut self, reconnect_policy: Option<ReconnectPolicy>) -> Self {
+    self.reconnect_policy = reconnect_policy;
+    self
+  }
+
+  /// Idle time before TCP keepalive probes are sent, `None` (the default) disables them.
+  pub fn tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> Self {
+    self.tcp_keepalive = tcp_keepalive;
+    self
+  }
+
+  /// Whether Nagle's algorithm is disabled on the socket, `true` by default.
+  pub fn tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+    self.tcp_nodelay = tcp_nodelay;
+    self
+  }
+
+  /// Received messages buffered for the dispatcher before reading from the socket pauses,
+  /// 32 by default.
+  pub fn inbound_capacity(mut self, inbound_capacity: usize) -> Self {
+    self.inbound_capacity = inbound_capacity.max(1);
+    self
+  }
+
+  /// Requests buffered for the writer before sending waits, 32 by default.
+  ///
+  /// A batch of `send_messages` takes a single slot.
+  pub fn outbound_capacity(mut self, outbound_capacity: usize) -> Self {
+    self.outbound_capacity = outbound_capacity.max(1);
+    self
+  }
+
+  /// Messages buffered per type for a subscriber before the oldest ones are dropped,
+  /// 64 by default.
+  pub fn subscription_capacity(mut self, subscription_capacity: usize) -> Self {
+    self.subscription_capacity = subscription_capacity.max(1);
+    self
+  }
+}
+
+/// Options for a device found by [`discover`](crate::discovery::discover), connecting to the
+/// addresses it announced.
+impl From<&ServiceInfo> for ClientOptions {
+  fn from(service: &ServiceInfo) -> Self {
+    ClientOptions::new(service.server.trim_end_matches('.'))
+      .port(service.port as u32)
+      .addresses(service.addresses.iter().copied())
+  }
+}
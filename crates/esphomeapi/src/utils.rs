@@ -1,6 +1,35 @@
+use std::collections::HashMap;
+
 use protobuf::{Message, MessageDyn, MessageFull};
 
-use crate::proto::api_options::exts::id;
+use crate::proto::{self, api_options::exts::id};
+
+lazy_static::lazy_static! {
+  static ref MESSAGE_NAMES: HashMap<u32, String> = proto::api::file_descriptor()
+    .messages()
+    .filter_map(|descriptor| {
+      let options = descriptor.proto().options.as_ref()?;
+      Some((id.get(options)?, descriptor.name().to_string()))
+    })
+    .collect();
+}
+
+/// Returns the ids of all messages whose name matches `predicate`.
+pub fn message_ids_where(predicate: impl Fn(&str) -> bool) -> Vec<u32> {
+  MESSAGE_NAMES
+    .iter()
+    .filter(|(_, name)| predicate(name))
+    .map(|(protobuf_type, _)| *protobuf_type)
+    .collect()
+}
+
+/// Returns the name of the message with the given id, e.g. "HelloResponse".
+pub fn message_name(protobuf_type: u32) -> &'static str {
+  MESSAGE_NAMES
+    .get(&protobuf_type)
+    .map(String::as_str)
+    .unwrap_or("Unknown")
+}
 
 pub trait Options {
   fn get_option_id() -> u32;
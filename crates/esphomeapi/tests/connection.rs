@@ -0,0 +1,768 @@
+use std::time::Duration;
+
+use base64::prelude::*;
+use bytes::BytesMut;
+use esphomeapi::{
+  api, model::EntityInfo, Client, ClientOptions, Connection, ConnectionEvent, ConnectionState,
+  DisconnectReason, Error, EspHomeCodec, EspHomeMessage, ListEntitiesItem, Options as _,
+  ReconnectEvent, ReconnectPolicy,
+};
+use futures::{SinkExt as _, StreamExt as _};
+use noise_protocol::{patterns::noise_nn_psk0, CipherState, HandshakeState};
+use noise_rust_crypto::{ChaCha20Poly1305, Sha256, X25519};
+use protobuf::{Message as _, MessageDyn, MessageFull};
+use tokio::{
+  io::{duplex, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream},
+  net::TcpListener,
+  sync::{broadcast, mpsc},
+  time::timeout,
+};
+use tokio_util::codec::{Encoder as _, Framed};
+
+/// Longest a test waits for anything before failing
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The device end of a connection, in memory unless a socket is given, speaking
+/// plaintext frames.
+struct Device<S = DuplexStream> {
+  framed: Framed<S, EspHomeCodec>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Device<S> {
+  fn new(stream: S) -> Self {
+    Device {
+      framed: Framed::new(stream, EspHomeCodec::plaintext()),
+    }
+  }
+
+  /// Reads the next message, which has to be an `M`. Pings are skipped unless asked for.
+  async fn recv<M: MessageFull>(&mut self) -> M {
+    loop {
+      let message = timeout(TIMEOUT, self.framed.next())
+        .await
+        .expect("timed out waiting for a message")
+        .expect("the client closed the stream")
+        .unwrap()
+        .into_protobuf_message();
+      if message.protobuf_type == api::PingRequest::get_option_id()
+        && M::get_option_id() != api::PingRequest::get_option_id()
+      {
+        continue;
+      }
+      assert_eq!(message.protobuf_type, M::get_option_id());
+      return M::parse_from_bytes(&message.protobuf_data).unwrap();
+    }
+  }
+
+  async fn send<M: MessageFull>(&mut self, message: M) {
+    let message =
+      EspHomeMessage::new_response(M::get_option_id(), message.write_to_bytes().unwrap());
+    self.framed.send(message).await.unwrap();
+  }
+
+  /// Answers the `HelloRequest` as `name` and accepts the `ConnectRequest`.
+  async fn accept(&mut self, name: &str) -> (api::HelloRequest, api::ConnectRequest) {
+    let hello = self.recv().await;
+    self.send(hello_response(name)).await;
+    let connect = self.recv().await;
+    self.send(api::ConnectResponse::new()).await;
+    (hello, connect)
+  }
+}
+
+const PSK: &str = "px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA=";
+
+/// The device end of an in-memory connection, speaking encrypted frames.
+struct NoiseDevice {
+  stream: DuplexStream,
+  decrypt: CipherState<ChaCha20Poly1305>,
+  encrypt: CipherState<ChaCha20Poly1305>,
+}
+
+impl NoiseDevice {
+  /// Answers the client's handshake, the server hello and the reply in a single write.
+  async fn accept(mut stream: DuplexStream) -> Self {
+    let mut responder: HandshakeState<X25519, ChaCha20Poly1305, Sha256> = HandshakeState::new(
+      noise_nn_psk0(),
+      false,
+      b"NoiseAPIInit\x00\x00",
+      None,
+      None,
+      None,
+      None,
+    );
+    responder.push_psk(&BASE64_STANDARD.decode(PSK).unwrap());
+
+    let mut hello = [0; 3];
+    stream.read_exact(&mut hello).await.unwrap();
+    let handshake = read_frame(&mut stream).await;
+    responder.read_message_vec(&handshake[1..]).unwrap();
+
+    let mut answer = frame(b"\x01kitchen\x00");
+    let mut reply = vec![0x00];
+    reply.extend(responder.write_message_vec(&[]).unwrap());
+    answer.extend(frame(&reply));
+    stream.write_all(&answer).await.unwrap();
+
+    let (decrypt, encrypt) = responder.get_ciphers();
+    NoiseDevice {
+      stream,
+      decrypt,
+      encrypt,
+    }
+  }
+
+  async fn recv<M: MessageFull>(&mut self) -> M {
+    let frame = timeout(TIMEOUT, read_frame(&mut self.stream))
+      .await
+      .expect("timed out waiting for a message");
+    let data = self.decrypt.decrypt_vec(&frame).unwrap();
+    assert_eq!(
+      u16::from_be_bytes([data[0], data[1]]) as u32,
+      M::get_option_id()
+    );
+    M::parse_from_bytes(&data[4..]).unwrap()
+  }
+
+  async fn send<M: MessageFull>(&mut self, message: M) {
+    let data = message.write_to_bytes().unwrap();
+    let mut plain = (M::get_option_id() as u16).to_be_bytes().to_vec();
+    plain.extend((data.len() as u16).to_be_bytes());
+    plain.extend(data);
+    let encrypted = self.encrypt.encrypt_vec(&plain);
+    self.stream.write_all(&frame(&encrypted)).await.unwrap();
+  }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+  let mut frame = vec![0x01];
+  frame.extend((payload.len() as u16).to_be_bytes());
+  frame.extend(payload);
+  frame
+}
+
+async fn read_frame(stream: &mut DuplexStream) -> Vec<u8> {
+  let mut header = [0; 3];
+  stream.read_exact(&mut header).await.unwrap();
+  assert_eq!(header[0], 0x01);
+  let mut payload = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+  stream.read_exact(&mut payload).await.unwrap();
+  payload
+}
+
+fn hello_response(name: &str) -> api::HelloResponse {
+  let mut response = api::HelloResponse::new();
+  response.api_version_major = 1;
+  response.api_version_minor = 10;
+  response.name = name.to_string();
+  response.server_info = "fake device".to_string();
+  response
+}
+
+fn options() -> ClientOptions {
+  ClientOptions::new("kitchen.local")
+    .password("secret")
+    .client_info("tests")
+}
+
+/// Connects a client to a device that accepts the login.
+async fn connect(options: ClientOptions) -> (Client, Device) {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options);
+  let mut device = Device::new(device_stream);
+  let (result, _) = tokio::join!(
+    client.connect_with_stream(client_stream, true),
+    device.accept("kitchen")
+  );
+  result.unwrap();
+  (client, device)
+}
+
+/// Waits for the next `Disconnected` event and returns its reason.
+async fn disconnected(events: &mut broadcast::Receiver<ConnectionEvent>) -> DisconnectReason {
+  timeout(TIMEOUT, async {
+    loop {
+      if let ConnectionEvent::Disconnected(reason) = events.recv().await.unwrap() {
+        return reason;
+      }
+    }
+  })
+  .await
+  .expect("timed out waiting for the disconnect")
+}
+
+#[tokio::test]
+async fn hello_and_login() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options());
+  let mut events = client.events();
+  let mut device = Device::new(device_stream);
+
+  let (result, (hello, connect)) = tokio::join!(
+    client.connect_with_stream(client_stream, true),
+    device.accept("kitchen")
+  );
+  result.unwrap();
+
+  assert_eq!(hello.client_info, "tests");
+  assert_eq!(hello.api_version_major, 1);
+  assert_eq!(connect.password, "secret");
+  assert!(client.is_connected());
+  assert_eq!(client.api_version_minor(), Some(10));
+  assert_eq!(client.server_info().as_deref(), Some("fake device"));
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::Connected
+  ));
+}
+
+#[tokio::test]
+async fn encrypted_hello_and_login() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options().psk(PSK.parse().unwrap()));
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let mut device = NoiseDevice::accept(device_stream).await;
+    let _: api::HelloRequest = device.recv().await;
+    device.send(hello_response("kitchen")).await;
+    let _: api::ConnectRequest = device.recv().await;
+    device.send(api::ConnectResponse::new()).await;
+  });
+  result.unwrap();
+
+  assert!(client.is_connected());
+  assert_eq!(client.state(), ConnectionState::Connected);
+}
+
+#[tokio::test]
+async fn rejected_psk_is_reported_as_such() {
+  let (client_stream, mut device_stream) = duplex(4096);
+  let mut client = Client::new(options().psk(PSK.parse().unwrap()));
+  let mut events = client.events();
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let mut hello = [0; 3];
+    device_stream.read_exact(&mut hello).await.unwrap();
+    read_frame(&mut device_stream).await;
+    let mut answer = frame(b"\x01kitchen\x00");
+    answer.extend(frame(b"\x01Handshake MAC failure"));
+    device_stream.write_all(&answer).await.unwrap();
+  });
+
+  assert!(matches!(result, Err(Error::PskRejected)));
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::HandshakeFailed(Error::PskRejected)
+  ));
+}
+
+#[tokio::test]
+async fn invalid_password() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options());
+  let mut events = client.events();
+  let mut device = Device::new(device_stream);
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let _: api::HelloRequest = device.recv().await;
+    device.send(hello_response("kitchen")).await;
+    let _: api::ConnectRequest = device.recv().await;
+    let mut response = api::ConnectResponse::new();
+    response.invalid_password = true;
+    device.send(response).await;
+  });
+
+  assert!(matches!(result, Err(Error::InvalidPassword)));
+  assert!(!client.is_connected());
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::AuthFailed
+  ));
+}
+
+#[tokio::test]
+async fn server_name_mismatch() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options().expected_name("kitchen"));
+  let mut device = Device::new(device_stream);
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let _: api::HelloRequest = device.recv().await;
+    device.send(hello_response("garage")).await;
+  });
+
+  match result {
+    Err(Error::ServerNameMismatch { expected, received }) => {
+      assert_eq!(expected, "kitchen");
+      assert_eq!(received, "garage");
+    }
+    other => panic!("expected a name mismatch, got {:?}", other),
+  }
+  assert!(!client.is_connected());
+}
+
+#[tokio::test]
+async fn requests_wait_for_the_login() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut connection = Connection::new(options());
+  let sender = connection.clone();
+  let mut device = Device::new(device_stream);
+
+  let (result, _) = tokio::join!(connection.connect_with_stream(client_stream, true), async {
+    let _: api::HelloRequest = device.recv().await;
+    device.send(hello_response("kitchen")).await;
+    let _: api::ConnectRequest = device.recv().await;
+    assert!(matches!(
+      sender.send(api::DeviceInfoRequest::new()).await,
+      Err(Error::NotConnected)
+    ));
+    device.send(api::ConnectResponse::new()).await;
+  });
+  result.unwrap();
+
+  sender.send(api::DeviceInfoRequest::new()).await.unwrap();
+  let _: api::DeviceInfoRequest = device.recv().await;
+}
+
+#[tokio::test]
+async fn device_closing_the_socket_is_reported() {
+  let (client, device) = connect(options()).await;
+  let mut events = client.events();
+
+  drop(device);
+
+  assert_eq!(
+    disconnected(&mut events).await,
+    DisconnectReason::SocketClosed
+  );
+  assert_eq!(client.state(), ConnectionState::Closed);
+  assert!(!client.is_connected());
+}
+
+#[tokio::test]
+async fn unanswered_pings_close_the_connection() {
+  let options = options()
+    .keep_alive(Duration::from_millis(20))
+    .max_missed_pings(2);
+  let (client, mut device) = connect(options).await;
+  let mut events = client.events();
+
+  // The device keeps reading, but never answers
+  let _: api::PingRequest = device.recv().await;
+
+  assert_eq!(
+    disconnected(&mut events).await,
+    DisconnectReason::KeepAliveTimeout
+  );
+  assert_eq!(client.state(), ConnectionState::Closed);
+}
+
+#[tokio::test]
+async fn keep_alive_times_out_while_the_writer_is_stuck() {
+  let options = options()
+    .keep_alive(Duration::from_millis(20))
+    .max_missed_pings(2)
+    .outbound_capacity(1);
+  let (client_stream, device_stream) = duplex(64);
+  let mut connection = Connection::new(options);
+  let mut events = connection.events();
+  let mut device = Device::new(device_stream);
+  let (result, _) = tokio::join!(
+    connection.connect_with_stream(client_stream, true),
+    device.accept("kitchen")
+  );
+  result.unwrap();
+
+  // The device stops reading, the writer blocks on the full stream and the queue fills up
+  let sender = connection.clone();
+  tokio::spawn(async move {
+    let mut request = api::TextCommandRequest::new();
+    request.state = "x".repeat(1024);
+    while sender.send(request.clone()).await.is_ok() {}
+  });
+
+  assert_eq!(
+    disconnected(&mut events).await,
+    DisconnectReason::KeepAliveTimeout
+  );
+  drop(device);
+}
+
+#[tokio::test]
+async fn graceful_disconnect() {
+  let (client, mut device) = connect(options()).await;
+  let mut events = client.events();
+
+  let (result, _) = tokio::join!(client.disconnect(false), async {
+    let _: api::DisconnectRequest = device.recv().await;
+    device.send(api::DisconnectResponse::new()).await;
+  });
+  result.unwrap();
+
+  assert_eq!(client.state(), ConnectionState::Closed);
+  assert_eq!(
+    disconnected(&mut events).await,
+    DisconnectReason::ClientRequest
+  );
+}
+
+#[tokio::test]
+async fn disconnect_fails_pending_requests() {
+  let (client, mut device) = connect(options()).await;
+
+  let (result, _) = tokio::join!(client.device_info(), async {
+    let _: api::DeviceInfoRequest = device.recv().await;
+    client.disconnect(true).await.unwrap();
+  });
+
+  assert!(matches!(
+    result,
+    Err(Error::Disconnected(DisconnectReason::ClientRequest))
+  ));
+}
+
+fn switch_info(key: u32) -> api::ListEntitiesSwitchResponse {
+  let mut info = api::ListEntitiesSwitchResponse::new();
+  info.key = key;
+  info.object_id = format!("switch_{}", key);
+  info
+}
+
+fn service_info(key: u32) -> api::ListEntitiesServicesResponse {
+  let mut info = api::ListEntitiesServicesResponse::new();
+  info.key = key;
+  info.name = format!("service_{}", key);
+  info
+}
+
+#[tokio::test]
+async fn listing_streams_items_until_done() {
+  let (client, mut device) = connect(options()).await;
+  let stream = client.list_entities_services_stream();
+
+  let (stream, _) = tokio::join!(stream, async {
+    let _: api::ListEntitiesRequest = device.recv().await;
+    device.send(switch_info(1)).await;
+    device.send(service_info(2)).await;
+    device.send(api::ListEntitiesDoneResponse::new()).await;
+    // Whatever follows the done message isn't part of the listing
+    device.send(switch_info(3)).await;
+  });
+  let items = timeout(TIMEOUT, stream.unwrap().collect::<Vec<_>>())
+    .await
+    .unwrap();
+
+  assert_eq!(items.len(), 2);
+  assert!(matches!(
+    &items[0],
+    Ok(ListEntitiesItem::Entity(EntityInfo::Switch(info))) if info.entity_info.key == 1
+  ));
+  assert!(matches!(
+    &items[1],
+    Ok(ListEntitiesItem::Service(service)) if service.key == 2
+  ));
+}
+
+#[tokio::test]
+async fn stalled_listing_fails_with_the_partial_result() {
+  let (client, mut device) = connect(options()).await;
+
+  let (result, _) = tokio::join!(
+    client.list_entities_services_timeout(Duration::from_millis(100)),
+    async {
+      let _: api::ListEntitiesRequest = device.recv().await;
+      device.send(switch_info(1)).await;
+      device.send(service_info(2)).await;
+    }
+  );
+
+  match result {
+    Err(Error::ListEntitiesTimeout { entities, services }) => {
+      assert!(matches!(
+        entities.as_slice(),
+        [EntityInfo::Switch(info)] if info.entity_info.key == 1
+      ));
+      assert_eq!(services.len(), 1);
+      assert_eq!(services[0].key, 2);
+    }
+    other => panic!("expected a listing timeout, got {:?}", other),
+  }
+  assert!(client.is_connected());
+}
+
+#[tokio::test]
+async fn one_shot_handler_runs_once() {
+  let (client, mut device) = connect(options()).await;
+  let switch_state = api::SwitchStateResponse::get_option_id();
+
+  let (once_tx, mut once) = mpsc::unbounded_channel();
+  client
+    .add_message_handler(
+      switch_state,
+      Box::new(move |_, message| {
+        let state = api::SwitchStateResponse::parse_from_bytes(&message.protobuf_data)?;
+        let _ = once_tx.send(state.key);
+        Ok(())
+      }),
+      true,
+    )
+    .detach();
+  let (dropped_tx, mut dropped) = mpsc::unbounded_channel::<u32>();
+  drop(client.add_message_handler(
+    switch_state,
+    Box::new(move |_, _| {
+      let _ = dropped_tx.send(0);
+      Ok(())
+    }),
+    false,
+  ));
+  let (every_tx, mut every) = mpsc::unbounded_channel();
+  let _every = client.add_message_handler(
+    switch_state,
+    Box::new(move |_, message| {
+      let state = api::SwitchStateResponse::parse_from_bytes(&message.protobuf_data)?;
+      let _ = every_tx.send(state.key);
+      Ok(())
+    }),
+    false,
+  );
+
+  for key in [1, 2] {
+    let mut state = api::SwitchStateResponse::new();
+    state.key = key;
+    device.send(state).await;
+  }
+  // Handlers run in order on a single task, so once the last one saw both messages
+  // the others are done with them too
+  assert_eq!(timeout(TIMEOUT, every.recv()).await.unwrap(), Some(1));
+  assert_eq!(timeout(TIMEOUT, every.recv()).await.unwrap(), Some(2));
+  assert_eq!(once.recv().await, Some(1));
+  assert!(once.try_recv().is_err());
+  assert!(dropped.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_messages_writes_each_batch_contiguously() {
+  const BATCHES: u32 = 16;
+  const BATCH_LEN: u32 = 4;
+
+  let (client_stream, device_stream) = duplex(4096);
+  let mut connection = Connection::new(options());
+  let mut device = Device::new(device_stream);
+  let (result, _) = tokio::join!(
+    connection.connect_with_stream(client_stream, true),
+    device.accept("kitchen")
+  );
+  result.unwrap();
+
+  let senders = (0..BATCHES)
+    .map(|batch| {
+      let connection = connection.clone();
+      tokio::spawn(async move {
+        let messages = (0..BATCH_LEN)
+          .map(|i| {
+            let mut request = api::SwitchCommandRequest::new();
+            request.key = batch * 100 + i;
+            Box::new(request) as Box<dyn MessageDyn>
+          })
+          .collect();
+        connection.send_messages(messages).await
+      })
+    })
+    .collect::<Vec<_>>();
+  for sender in senders {
+    sender.await.unwrap().unwrap();
+  }
+
+  let mut keys = Vec::new();
+  for _ in 0..BATCHES * BATCH_LEN {
+    let request: api::SwitchCommandRequest = device.recv().await;
+    keys.push(request.key);
+  }
+  for run in keys.chunks(BATCH_LEN as usize) {
+    let first = run[0];
+    assert_eq!(first % 100, 0, "a batch was split: {:?}", keys);
+    let expected = (first..first + BATCH_LEN).collect::<Vec<_>>();
+    assert_eq!(run, expected, "a batch was split: {:?}", keys);
+  }
+}
+
+#[tokio::test]
+async fn reconnects_after_the_device_drops_the_socket() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let policy = ReconnectPolicy {
+    initial_delay: Duration::from_millis(10),
+    jitter: 0.0,
+    ..Default::default()
+  };
+  let options = ClientOptions::new("127.0.0.1")
+    .port(listener.local_addr().unwrap().port() as u32)
+    .password("secret")
+    .reconnect_policy(Some(policy));
+  let mut client = Client::new(options);
+  let mut events = client.events();
+  let mut reconnect_events = client.reconnect_events();
+
+  let (result, device) = tokio::join!(client.connect(true), async {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut device = Device::new(stream);
+    device.accept("kitchen").await;
+    device
+  });
+  result.unwrap();
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::Connected
+  ));
+
+  let (states_tx, mut states) = mpsc::unbounded_channel();
+  let _handler = client.add_message_handler(
+    api::SwitchStateResponse::get_option_id(),
+    Box::new(move |_, message| {
+      let state = api::SwitchStateResponse::parse_from_bytes(&message.protobuf_data)?;
+      let _ = states_tx.send(state.key);
+      Ok(())
+    }),
+    false,
+  );
+
+  drop(device);
+  assert_eq!(
+    disconnected(&mut events).await,
+    DisconnectReason::SocketClosed
+  );
+
+  let (stream, _) = timeout(TIMEOUT, listener.accept())
+    .await
+    .expect("the client didn't reconnect")
+    .unwrap();
+  let mut device = Device::new(stream);
+  let (_, connect) = device.accept("kitchen").await;
+  assert_eq!(connect.password, "secret");
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::Connected
+  ));
+  assert!(client.is_connected());
+
+  assert!(matches!(
+    reconnect_events.recv().await.unwrap(),
+    ReconnectEvent::Scheduled { attempt: 1, delay } if delay == Duration::from_millis(10)
+  ));
+  assert!(matches!(
+    reconnect_events.recv().await.unwrap(),
+    ReconnectEvent::Connected { attempt: 1 }
+  ));
+
+  // Handlers registered before the drop keep receiving messages
+  let mut state = api::SwitchStateResponse::new();
+  state.key = 7;
+  device.send(state).await;
+  assert_eq!(timeout(TIMEOUT, states.recv()).await.unwrap(), Some(7));
+}
+
+#[tokio::test]
+async fn peer_addr_is_the_address_that_answered() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  // The listener is bound to 127.0.0.1 only, so the first address refuses the connection
+  let options = ClientOptions::new("kitchen.local")
+    .addresses(["127.0.0.2".parse().unwrap(), "127.0.0.1".parse().unwrap()])
+    .port(port as u32)
+    .password("secret");
+  let mut client = Client::new(options);
+  assert_eq!(client.peer_addr(), None);
+
+  let (result, _device) = tokio::join!(client.connect(true), async {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut device = Device::new(stream);
+    device.accept("kitchen").await;
+    device
+  });
+  result.unwrap();
+  assert_eq!(client.peer_addr(), Some(([127, 0, 0, 1], port).into()));
+}
+
+#[tokio::test]
+async fn falls_back_to_plaintext_without_reporting_a_failed_handshake() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let options = ClientOptions::new("127.0.0.1")
+    .port(listener.local_addr().unwrap().port() as u32)
+    .password("secret")
+    .psk(PSK.parse().unwrap())
+    .plaintext_fallback(true);
+  let mut client = Client::new(options);
+  let mut events = client.events();
+
+  let (result, _device) = tokio::join!(client.connect(true), async {
+    // A plaintext device answers the encrypted hello with a plaintext frame
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut hello = [0; 3];
+    stream.read_exact(&mut hello).await.unwrap();
+    stream.write_all(&[0x00, 0x00, 0x00]).await.unwrap();
+    drop(stream);
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut device = Device::new(stream);
+    device.accept("kitchen").await;
+    device
+  });
+  result.unwrap();
+
+  assert!(client.is_connected());
+  assert!(matches!(
+    events.recv().await.unwrap(),
+    ConnectionEvent::Connected
+  ));
+}
+
+#[tokio::test]
+async fn device_requiring_encryption_is_reported_as_such() {
+  let (client_stream, mut device_stream) = duplex(4096);
+  let mut client = Client::new(options());
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let mut hello = [0; 1];
+    device_stream.read_exact(&mut hello).await.unwrap();
+    device_stream
+      .write_all(&frame(b"\x01Only encrypted connections are allowed"))
+      .await
+      .unwrap();
+  });
+
+  assert!(matches!(result, Err(Error::RequiresEncryption)));
+  assert!(!client.is_connected());
+  assert_eq!(client.state(), ConnectionState::Closed);
+}
+
+#[tokio::test]
+async fn login_timeout_covers_the_whole_answer() {
+  let (client_stream, device_stream) = duplex(4096);
+  let mut client = Client::new(options().login_timeout(Duration::from_millis(200)));
+  let mut device = Device::new(device_stream);
+
+  let (result, _) = tokio::join!(client.connect_with_stream(client_stream, true), async {
+    let _: api::HelloRequest = device.recv().await;
+    let message = EspHomeMessage::new_response(
+      api::HelloResponse::get_option_id(),
+      hello_response("kitchen").write_to_bytes().unwrap(),
+    );
+    let mut answer = BytesMut::new();
+    EspHomeCodec::plaintext()
+      .encode(message, &mut answer)
+      .unwrap();
+    // Each part arrives within the timeout, the whole answer doesn't
+    let stream = device.framed.get_mut();
+    tokio::time::sleep(Duration::from_millis(130)).await;
+    stream.write_all(&answer[..1]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(130)).await;
+    // The client may have given up and closed the stream already
+    let _ = stream.write_all(&answer[1..]).await;
+    if let Some(Ok(_)) = timeout(TIMEOUT, device.framed.next()).await.unwrap() {
+      device.send(api::ConnectResponse::new()).await;
+    }
+  });
+
+  assert!(matches!(result, Err(Error::Timeout)));
+  assert_eq!(client.state(), ConnectionState::Closed);
+}